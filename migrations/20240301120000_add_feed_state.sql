@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS feed_state
+(
+    feed_name VARCHAR (32) PRIMARY KEY,
+    etag VARCHAR,
+    last_modified VARCHAR,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+)
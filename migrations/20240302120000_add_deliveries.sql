@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS deliveries
+(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    feed_item_id UUID NOT NULL REFERENCES feed_items (id) ON DELETE CASCADE,
+    receiver VARCHAR NOT NULL,
+    status VARCHAR (16) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_error VARCHAR,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (feed_item_id, receiver)
+);
+
+CREATE INDEX IF NOT EXISTS deliveries_status_next_attempt_at_idx ON deliveries (status, next_attempt_at);
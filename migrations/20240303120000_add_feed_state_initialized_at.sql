@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE feed_state ADD COLUMN IF NOT EXISTS initialized_at TIMESTAMP WITH TIME ZONE;
+
+-- feeds that were polled before are already initialized
+UPDATE feed_state SET initialized_at = updated_at WHERE initialized_at IS NULL;
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- pub_date is excluded since it falls back to the fetch time for items without a date, and
+-- feed_title since renaming a feed would otherwise edit every message
+ALTER TABLE feed_items ADD COLUMN IF NOT EXISTS variables_hash VARCHAR (32)
+    GENERATED ALWAYS AS (md5((variables - 'pub_date' - 'feed_title')::text)) STORED;
+
+ALTER TABLE deliveries ADD COLUMN IF NOT EXISTS action VARCHAR (16) NOT NULL DEFAULT 'send';
+ALTER TABLE deliveries ADD COLUMN IF NOT EXISTS message_ref VARCHAR;
+-- type of the receiver that sent the message, the reference means nothing to other types
+ALTER TABLE deliveries ADD COLUMN IF NOT EXISTS message_ref_type VARCHAR (16);
+-- action queued once the delivery being sent is done, as its item changed in the meantime
+ALTER TABLE deliveries ADD COLUMN IF NOT EXISTS pending_action VARCHAR (16);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE feed_items ADD COLUMN IF NOT EXISTS removed_at TIMESTAMP WITH TIME ZONE;
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use serde_derive::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Config {
@@ -15,30 +15,232 @@ pub struct ConfigFeed {
     pub guid_regex: Option<String>,
     pub receivers: Vec<ConfigFeedReceiver>,
     pub user_agent: Option<String>,
+    /// Deprecated, use `format: atom` instead
     pub atom: Option<bool>,
+    pub format: Option<ConfigFeedFormat>,
+    pub max_delivery_attempts: Option<u32>,
+    /// strftime format tried before the built-in formats when parsing item dates
+    pub date_format: Option<String>,
+    /// What to deliver the first time the feed is processed, defaults to `all`
+    pub initial_sync: Option<ConfigFeedInitialSync>,
+    /// Amount of items delivered by `initial_sync: latest_n`, defaults to 1
+    pub initial_sync_count: Option<usize>,
+    /// Items that don't match are recorded as seen but not delivered to any receiver
+    pub filter: Option<ConfigFilter>,
+    /// Seconds after an item was first seen in which it is tracked for removal, defaults to a day
+    pub removal_window: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigFeedInitialSync {
+    /// Record the existing items as seen without delivering any of them
+    Skip,
+    /// Only deliver the newest `initial_sync_count` existing items
+    LatestN,
+    /// Deliver every existing item
+    All,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ConfigFeedReceiver {
-    #[serde(rename = "type")]
+    /// Identifies the receiver in the delivery queue, defaults to its type and a hash of its
+    /// target, required when several receivers share a target
+    pub id: Option<String>,
+    /// Items that don't match are not delivered to this receiver
+    pub filter: Option<ConfigFilter>,
+    /// Edit the delivered message when the item changes afterwards
+    #[serde(default)]
+    pub edits: bool,
+    /// What to do with the delivered message when the item is removed from the feed
+    #[serde(default)]
+    pub on_removed: ConfigFeedReceiverRemoval,
+    /// Collect new items and send them as a single summary on a schedule
+    pub digest: Option<ConfigFeedReceiverDigest>,
+    /// Hold items during these hours and deliver them afterwards
+    pub quiet_hours: Option<ConfigFeedReceiverQuietHours>,
+    /// Limit the amount of items delivered per window, the rest is sent as a single summary
+    pub throttle: Option<ConfigFeedReceiverThrottle>,
+    #[serde(flatten)]
     pub receiver_type: ConfigFeedReceiverType,
-    pub discord: ConfigFeedDiscordReceiver,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
+pub struct ConfigFeedReceiverDigest {
+    /// Cron expression including seconds in UTC, e.g. `0 0 9 * * *` for every day at 09:00
+    pub schedule: Option<String>,
+    /// Seconds between digests, counted from the first item waiting for the digest
+    pub interval: Option<u64>,
+    /// Heading of the digest, rendered with the variables of the first item and `$count`
+    pub title: Option<String>,
+    /// Line rendered for every item in the digest
+    pub line: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedReceiverQuietHours {
+    /// Start time as `HH:MM`
+    pub start: String,
+    /// End time as `HH:MM`, before the start when the quiet hours span midnight
+    pub end: String,
+    /// Timezone of the start and end time, e.g. `Europe/Amsterdam`, defaults to UTC
+    pub timezone: Option<String>,
+    /// Items matching this filter are delivered during the quiet hours as well
+    pub urgent: Option<ConfigFilter>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedReceiverThrottle {
+    /// Maximum amount of items delivered per window
+    pub max: usize,
+    /// Length of the window in seconds
+    pub window: u64,
+    /// Heading of the summary of the items over the limit, rendered with the variables of the
+    /// first item and `$count`
+    pub overflow: Option<String>,
+    /// Line rendered for every item in the summary
+    pub line: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigFeedReceiverRemoval {
+    #[default]
+    Nothing,
+    Delete,
+    /// Edit the message to mark it as retracted
+    Retract,
+}
+
+/// Rule on the item variables, every condition that is set has to hold for the rule to match
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigFilter {
+    /// Variable the conditions apply to, `published_at` is available as RFC 3339 date
+    pub field: Option<String>,
+    pub regex: Option<String>,
+    pub not_regex: Option<String>,
+    /// Matches when the value contains any of the keywords, case insensitive
+    #[serde(default)]
+    pub contains_any: Vec<String>,
+    /// Compared as number when both sides are numeric, as date otherwise
+    pub greater_than: Option<String>,
+    pub less_than: Option<String>,
+    #[serde(default)]
+    pub all: Vec<ConfigFilter>,
+    #[serde(default)]
+    pub any: Vec<ConfigFilter>,
+    pub not: Option<Box<ConfigFilter>>,
+}
+
+/// Selected by `type`, every type requires the block of the same name
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum ConfigFeedReceiverType {
-    Discord,
+    Discord {
+        discord: ConfigFeedDiscordReceiver,
+    },
+    Slack {
+        slack: ConfigFeedSlackReceiver,
+    },
+    Matrix {
+        matrix: ConfigFeedMatrixReceiver,
+    },
+    Telegram {
+        telegram: ConfigFeedTelegramReceiver,
+    },
+    Webhook {
+        webhook: ConfigFeedWebhookReceiver,
+    },
+    Email {
+        email: ConfigFeedEmailReceiver,
+    },
+}
+
+impl ConfigFeedReceiverType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigFeedReceiverType::Discord { .. } => "discord",
+            ConfigFeedReceiverType::Slack { .. } => "slack",
+            ConfigFeedReceiverType::Matrix { .. } => "matrix",
+            ConfigFeedReceiverType::Telegram { .. } => "telegram",
+            ConfigFeedReceiverType::Webhook { .. } => "webhook",
+            ConfigFeedReceiverType::Email { .. } => "email",
+        }
+    }
+
+    /// Where the receiver delivers to, e.g. the webhook or the chat
+    pub fn target(&self) -> String {
+        match self {
+            ConfigFeedReceiverType::Discord { discord } => format!(
+                "{}\n{}",
+                discord.webhook_url,
+                discord.thread_id.as_deref().unwrap_or_default()
+            ),
+            ConfigFeedReceiverType::Slack { slack } => slack.webhook_url.clone(),
+            ConfigFeedReceiverType::Matrix { matrix } => {
+                format!("{}\n{}", matrix.homeserver_url, matrix.room)
+            }
+            ConfigFeedReceiverType::Telegram { telegram } => format!(
+                "{}\n{}",
+                telegram.chat_id,
+                telegram.message_thread_id.unwrap_or_default()
+            ),
+            ConfigFeedReceiverType::Webhook { webhook } => webhook.url.clone(),
+            ConfigFeedReceiverType::Email { email } => {
+                format!("{}\n{}", email.host, email.to.join(","))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ConfigFeedDiscordReceiver {
     pub webhook_url: String,
     pub content: Option<String>,
+    /// Overrides the default username of the webhook
+    pub username: Option<String>,
+    /// Overrides the default avatar of the webhook
+    pub avatar_url: Option<String>,
+    /// Posts into an existing thread or forum post
+    pub thread_id: Option<String>,
+    /// Creates a forum post with this name for every item
+    pub thread_name: Option<String>,
+    /// Forum tag ids by category name, applied to the forum posts for the item categories
+    #[serde(default)]
+    pub forum_tags: BTreeMap<String, String>,
     #[serde(default)]
     pub embeds: Vec<ConfigFeedDiscordReceiverEmbed>,
     #[serde(default)]
     pub overrides: Vec<ConfigFeedDiscordReceiverOverride>,
+    /// Sends new items together in a single message instead of one message per item
+    pub batch: Option<ConfigFeedDiscordReceiverBatch>,
+    /// Role ids to ping for every item, other mentions in the content never ping
+    #[serde(default)]
+    pub mention_roles: Vec<String>,
+    /// User ids to ping for every item
+    #[serde(default)]
+    pub mention_users: Vec<String>,
+    /// Prefixed to the content when a message is edited after the item changed
+    pub updated_marker: Option<String>,
+    /// Prefixed to the content when the item is removed from the feed, titles are struck through
+    pub retracted_marker: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedDiscordReceiverBatch {
+    /// Maximum amount of items per message, at most 10
+    pub max_size: Option<usize>,
+    /// Content of a message with multiple items, rendered with the variables of the first item
+    /// and `$count`, e.g. `$count new items from $feed_title`
+    pub content: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -47,6 +249,12 @@ pub struct ConfigFeedDiscordReceiverOverride {
     pub field: String,
     pub webhook_url: Option<String>,
     pub content: Option<String>,
+    /// Role ids to ping when the override matches
+    #[serde(default)]
+    pub mention_roles: Vec<String>,
+    /// User ids to ping when the override matches
+    #[serde(default)]
+    pub mention_users: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -56,9 +264,23 @@ pub struct ConfigFeedDiscordReceiverEmbed {
     #[serde(default)]
     pub fields: Vec<ConfigFeedDiscordReceiverEmbedField>,
     pub footer: Option<String>,
+    pub footer_icon_url: Option<String>,
     pub image: Option<String>,
     pub thumbnail: Option<String>,
     pub url: Option<String>,
+    /// Hex (`#5865f2`) or decimal color, after substituting variables
+    pub color: Option<String>,
+    pub author: Option<ConfigFeedDiscordReceiverEmbedAuthor>,
+    /// Adds the published date of the item, rendered in the local time of the reader
+    #[serde(default)]
+    pub timestamp: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedDiscordReceiverEmbedAuthor {
+    pub name: String,
+    pub url: Option<String>,
+    pub icon_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -75,3 +297,141 @@ impl Config {
         Ok(config)
     }
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedSlackReceiver {
+    /// Incoming webhook url, e.g. `https://hooks.slack.com/services/...`
+    pub webhook_url: String,
+    /// Text shown in notifications, and in the channel when there are no blocks
+    pub text: Option<String>,
+    /// Block Kit blocks, variables in mrkdwn texts are escaped
+    #[serde(default)]
+    pub blocks: Vec<ConfigFeedSlackReceiverBlock>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConfigFeedSlackReceiverBlock {
+    Header {
+        text: String,
+    },
+    Section {
+        /// Shown as mrkdwn
+        text: String,
+        /// Image shown next to the text
+        image_url: Option<String>,
+    },
+    Image {
+        image_url: String,
+        alt_text: Option<String>,
+        title: Option<String>,
+    },
+    Context {
+        /// Shown as mrkdwn, at most 10
+        elements: Vec<String>,
+    },
+    Button {
+        text: String,
+        url: String,
+        /// `primary` or `danger`
+        style: Option<String>,
+    },
+    Divider,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedMatrixReceiver {
+    /// Base url of the homeserver, e.g. `https://matrix.org`
+    pub homeserver_url: String,
+    pub access_token: String,
+    /// Room id like `!abc:matrix.org` or alias like `#news:matrix.org`
+    pub room: String,
+    /// `m.text` or `m.notice`, defaults to `m.text`
+    pub msgtype: Option<String>,
+    /// Plain text body
+    pub body: Option<String>,
+    /// HTML body, variables are escaped
+    pub formatted_body: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedTelegramReceiver {
+    /// Base url of the Bot API, defaults to `https://api.telegram.org`
+    pub api_url: Option<String>,
+    pub bot_token: String,
+    /// Chat id, or `@username` for public channels
+    pub chat_id: String,
+    /// Topic to post in when the chat is a forum
+    pub message_thread_id: Option<i64>,
+    #[serde(default)]
+    pub parse_mode: ConfigFeedTelegramParseMode,
+    /// Text of the message, or caption of the photo; variables are escaped for the parse mode
+    pub text: Option<String>,
+    /// Url of a photo to send with the text as caption, e.g. `$enclosure_url`, items without
+    /// the variables are sent as text
+    pub photo: Option<String>,
+    #[serde(default)]
+    pub disable_web_page_preview: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigFeedTelegramParseMode {
+    #[default]
+    Html,
+    MarkdownV2,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedWebhookReceiver {
+    pub url: String,
+    /// Defaults to `POST`
+    pub method: Option<String>,
+    /// Header values are expanded with the item variables
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// JSON body, every string in it is expanded with the item variables
+    pub json: Option<serde_json::Value>,
+    /// Text body, used when there is no JSON body
+    pub body: Option<String>,
+    /// Shared secret to sign the body with HMAC-SHA256
+    pub secret: Option<String>,
+    /// Header holding the signature as `sha256=<hex>`, defaults to `X-Signature-256`
+    pub signature_header: Option<String>,
+    /// Status codes counted as delivered, defaults to every 2xx status
+    #[serde(default)]
+    pub success_codes: Vec<u16>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigFeedEmailReceiver {
+    /// SMTP server
+    pub host: String,
+    /// Defaults to 587 for STARTTLS, 465 for implicit TLS and 25 without TLS
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: ConfigFeedEmailTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender, e.g. `Feeds <feeds@example.com>`
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub cc: Vec<String>,
+    pub subject: Option<String>,
+    /// Plain text body
+    pub text: Option<String>,
+    /// HTML body, variables are escaped
+    pub html: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigFeedEmailTls {
+    #[default]
+    Starttls,
+    /// TLS from the start of the connection, usually on port 465
+    Implicit,
+    /// Plain text connection, only meant for local mail servers
+    None,
+}
@@ -5,13 +5,17 @@ use std::{
 };
 
 use anyhow::Result;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
 use log::info;
-use serde_json::json;
+use serde_json::{json, Value};
 use sqlx::{
-    postgres::{PgConnectOptions, PgPoolOptions},
+    postgres::{PgConnectOptions, PgPoolOptions, PgRow},
+    types::Uuid,
     Executor, Pool, Postgres, QueryBuilder, Row,
 };
+use subst::VariableMap;
+
+use crate::markdown::{escape_markdown, html_to_markdown, html_to_text};
 
 #[derive(Clone)]
 pub struct Database {
@@ -26,9 +30,117 @@ pub struct DatabaseFeedItem {
     pub variables: BTreeMap<String, String>,
 }
 
+#[derive(Clone)]
+pub struct DatabaseDelivery {
+    pub id: Uuid,
+    pub receiver: String,
+    pub attempts: i32,
+    pub action: DatabaseDeliveryAction,
+    /// Reference to the message sent by the receiver, used to edit it afterwards
+    pub message_ref: Option<String>,
+    /// Type of the receiver that sent the message
+    pub message_ref_type: Option<String>,
+    pub item: DatabaseFeedItem,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DatabaseDeliveryAction {
+    Send,
+    Update,
+    /// The item was removed from the feed, delete the message
+    Delete,
+    /// The item was removed from the feed, mark the message as retracted
+    Retract,
+    /// The item is waiting for the next digest of the receiver
+    Digest,
+    /// The receiver was throttled, the item is waiting to be summarized
+    Overflow,
+}
+
+impl DatabaseDeliveryAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DatabaseDeliveryAction::Send => "send",
+            DatabaseDeliveryAction::Update => "update",
+            DatabaseDeliveryAction::Delete => "delete",
+            DatabaseDeliveryAction::Retract => "retract",
+            DatabaseDeliveryAction::Digest => "digest",
+            DatabaseDeliveryAction::Overflow => "overflow",
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DatabaseFeedState {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Whether the initial sync of the feed has been done
+    pub initialized: bool,
+}
+
 impl DatabaseFeedItem {
     pub fn sub(&self, input: &str) -> String {
-        subst::substitute(input, &self.variables).unwrap_or(input.to_owned())
+        self.try_sub(input).unwrap_or(input.to_owned())
+    }
+
+    /// Substitutes the variables, `None` when one of them is missing
+    pub fn try_sub(&self, input: &str) -> Option<String> {
+        subst::substitute(input, self).ok()
+    }
+
+    /// Looks up a variable, every variable also has a `_text` variant with the HTML stripped,
+    /// a `_md` variant converted to markdown and an `_escaped` variant safe to use in markdown
+    pub fn variable(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.variables.get(key) {
+            return Some(value.clone());
+        }
+
+        if let Some(value) = key
+            .strip_suffix("_text")
+            .and_then(|k| self.variables.get(k))
+        {
+            return Some(html_to_text(value));
+        }
+
+        if let Some(value) = key.strip_suffix("_md").and_then(|k| self.variables.get(k)) {
+            return Some(html_to_markdown(value));
+        }
+
+        if let Some(value) = key
+            .strip_suffix("_escaped")
+            .and_then(|k| self.variables.get(k))
+        {
+            return Some(escape_markdown(value));
+        }
+
+        None
+    }
+
+    /// Substitutes the variables like `sub`, escaping their values for the target format
+    pub fn sub_escaped(&self, input: &str, escape: fn(&str) -> String) -> String {
+        subst::substitute(input, &EscapedVariables { item: self, escape })
+            .unwrap_or(input.to_owned())
+    }
+}
+
+struct EscapedVariables<'a> {
+    item: &'a DatabaseFeedItem,
+    escape: fn(&str) -> String,
+}
+
+impl<'a> VariableMap<'a> for EscapedVariables<'_> {
+    type Value = String;
+
+    fn get(&'a self, key: &str) -> Option<Self::Value> {
+        self.item.variable(key).map(|v| (self.escape)(&v))
+    }
+}
+
+impl<'a> VariableMap<'a> for DatabaseFeedItem {
+    type Value = String;
+
+    fn get(&'a self, key: &str) -> Option<Self::Value> {
+        self.variable(key)
     }
 }
 
@@ -60,10 +172,18 @@ impl Database {
         Ok(migrations_start.elapsed())
     }
 
-    pub async fn insert_and_select_feed_items(
+    /// Inserts the items and queues a delivery for every new item and receiver in a
+    /// single transaction, items whose variables changed are updated and their delivered
+    /// messages queued for an edit for the `edit_receivers`, returns the external ids of
+    /// the new and the updated items
+    pub async fn insert_feed_items_and_enqueue(
         &self,
-        items: &Vec<DatabaseFeedItem>,
-    ) -> Result<Vec<String>> {
+        items: &[DatabaseFeedItem],
+        receivers: &[String],
+        edit_receivers: &[String],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let mut tx = self.pool.begin().await?;
+
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
             "INSERT INTO feed_items (feed_name, external_id, published_at, variables) ",
         );
@@ -75,12 +195,551 @@ impl Database {
                 .push_bind(json!(new_item.variables));
         });
 
-        query_builder.push("ON CONFLICT (feed_name, external_id) DO NOTHING RETURNING external_id");
+        // xmax is only zero for inserted rows, the stored pub_date is kept as it may be
+        // derived from the fetch time
+        query_builder.push(
+            "ON CONFLICT (feed_name, external_id) DO UPDATE \
+            SET variables = EXCLUDED.variables || jsonb_strip_nulls(jsonb_build_object('pub_date', feed_items.variables -> 'pub_date')) \
+            WHERE feed_items.variables_hash IS DISTINCT FROM md5((EXCLUDED.variables - 'pub_date' - 'feed_title')::text) \
+            RETURNING id, external_id, xmax = 0",
+        );
 
         let query = query_builder.build();
 
-        let rows = self.pool.fetch_all(query).await?;
+        let rows = tx.fetch_all(query).await?;
+
+        let (new_items, updated_items): (Vec<_>, Vec<_>) = rows
+            .iter()
+            .map(|r| -> (Uuid, String, bool) { (r.get(0), r.get(1), r.get(2)) })
+            .partition(|(_, _, inserted)| *inserted);
+
+        if !new_items.is_empty() && !receivers.is_empty() {
+            let mut query_builder: QueryBuilder<Postgres> =
+                QueryBuilder::new("INSERT INTO deliveries (feed_item_id, receiver) ");
+
+            query_builder.push_values(
+                new_items
+                    .iter()
+                    .flat_map(|(id, _, _)| receivers.iter().map(move |r| (id, r))),
+                |mut b, (id, receiver)| {
+                    b.push_bind(*id).push_bind(receiver.clone());
+                },
+            );
+
+            query_builder.push("ON CONFLICT (feed_item_id, receiver) DO NOTHING");
+
+            tx.execute(query_builder.build()).await?;
+        }
+
+        if !updated_items.is_empty() && !edit_receivers.is_empty() {
+            let ids: Vec<Uuid> = updated_items.iter().map(|(id, _, _)| *id).collect();
+
+            // deliveries that are not sent yet will pick up the new variables by themselves
+            sqlx::query(
+                "UPDATE deliveries SET action = 'update', status = 'pending', attempts = 0, \
+                next_attempt_at = NOW(), last_error = NULL, updated_at = NOW() \
+                WHERE feed_item_id = ANY($1) AND receiver = ANY($2) \
+                AND status = 'delivered' AND message_ref IS NOT NULL",
+            )
+            .bind(&ids)
+            .bind(edit_receivers)
+            .execute(&mut *tx)
+            .await?;
+
+            // deliveries being sent use the old variables, they are updated once they are sent
+            sqlx::query(
+                "UPDATE deliveries SET pending_action = COALESCE(pending_action, 'update') \
+                WHERE feed_item_id = ANY($1) AND receiver = ANY($2) \
+                AND status = 'sending' AND action IN ('send', 'update')",
+            )
+            .bind(&ids)
+            .bind(edit_receivers)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok((
+            new_items.into_iter().map(|(_, id, _)| id).collect(),
+            updated_items.into_iter().map(|(_, id, _)| id).collect(),
+        ))
+    }
+
+    /// Marks stored items that are missing from the feed as removed and queues the delete or
+    /// retract of their delivered messages, deliveries not sent yet are cancelled. Only items
+    /// first seen within the window and stored as published after the oldest present item are
+    /// considered since older items simply fell off the feed, returns the external ids of the
+    /// removed items
+    pub async fn mark_removed_feed_items(
+        &self,
+        feed_name: &str,
+        present: &[String],
+        window: TimeDelta,
+        delete_receivers: &[String],
+        retract_receivers: &[String],
+    ) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+
+        // items can come back after being pulled temporarily
+        sqlx::query(
+            "UPDATE feed_items SET removed_at = NULL \
+            WHERE feed_name = $1 AND external_id = ANY($2) AND removed_at IS NOT NULL",
+        )
+        .bind(feed_name)
+        .bind(present)
+        .execute(&mut *tx)
+        .await?;
+
+        // the stored dates are compared, items without a date of their own are stored with the
+        // time they were first seen instead of the time of every fetch
+        let rows = sqlx::query(
+            "UPDATE feed_items SET removed_at = NOW() \
+            WHERE feed_name = $1 AND NOT (external_id = ANY($2)) AND removed_at IS NULL \
+            AND created_at >= NOW() - $3 \
+            AND published_at >= ( \
+                SELECT MIN(published_at) FROM feed_items \
+                WHERE feed_name = $1 AND external_id = ANY($2) \
+            ) \
+            RETURNING id, external_id",
+        )
+        .bind(feed_name)
+        .bind(present)
+        .bind(window)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let ids: Vec<Uuid> = rows.iter().map(|r| r.get(0)).collect();
+
+        for (action, receivers) in [("delete", delete_receivers), ("retract", retract_receivers)] {
+            if ids.is_empty() || receivers.is_empty() {
+                continue;
+            }
+
+            // a pending delivery with a message is an edit that is waiting for a retry
+            sqlx::query(
+                "UPDATE deliveries SET action = $1, status = 'pending', attempts = 0, \
+                next_attempt_at = NOW(), last_error = NULL, updated_at = NOW() \
+                WHERE feed_item_id = ANY($2) AND receiver = ANY($3) \
+                AND status IN ('delivered', 'pending') AND message_ref IS NOT NULL",
+            )
+            .bind(action)
+            .bind(&ids)
+            .bind(receivers)
+            .execute(&mut *tx)
+            .await?;
+
+            // this covers deliveries in backoff, held by quiet hours or throttling and waiting
+            // for a digest
+            sqlx::query(
+                "UPDATE deliveries SET status = 'cancelled', updated_at = NOW() \
+                WHERE feed_item_id = ANY($1) AND receiver = ANY($2) \
+                AND status = 'pending' AND message_ref IS NULL",
+            )
+            .bind(&ids)
+            .bind(receivers)
+            .execute(&mut *tx)
+            .await?;
+
+            // messages being sent are removed once they are sent
+            sqlx::query(
+                "UPDATE deliveries SET pending_action = $1 \
+                WHERE feed_item_id = ANY($2) AND receiver = ANY($3) AND status = 'sending'",
+            )
+            .bind(action)
+            .bind(&ids)
+            .bind(receivers)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(rows.iter().map(|r| r.get(1)).collect())
+    }
+
+    /// Claims the deliveries of a feed that are due, a delivery stuck in `sending` for
+    /// longer than the lock timeout is considered abandoned and claimed again
+    pub async fn claim_deliveries(
+        &self,
+        feed_name: &str,
+        limit: i64,
+    ) -> Result<Vec<DatabaseDelivery>> {
+        self.cancel_abandoned_removed_deliveries(feed_name).await?;
+
+        let rows = sqlx::query(
+            "UPDATE deliveries d SET status = 'sending', updated_at = NOW() \
+            FROM feed_items i \
+            WHERE d.feed_item_id = i.id AND d.id IN ( \
+                SELECT d.id FROM deliveries d \
+                JOIN feed_items i ON i.id = d.feed_item_id \
+                WHERE i.feed_name = $1 \
+                AND d.next_attempt_at <= NOW() \
+                AND (d.status = 'pending' OR (d.status = 'sending' AND d.updated_at < NOW() - INTERVAL '5 minutes')) \
+                ORDER BY i.published_at \
+                LIMIT $2 \
+                FOR UPDATE OF d SKIP LOCKED \
+            ) \
+            RETURNING d.id, d.receiver, d.attempts, d.action, d.message_ref, d.message_ref_type, \
+            i.feed_name, i.external_id, i.published_at, i.variables",
+        )
+        .bind(feed_name)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut deliveries = rows
+            .iter()
+            .map(delivery_from_row)
+            .collect::<Vec<DatabaseDelivery>>();
+
+        deliveries.sort_by_key(|d| d.item.published_at);
+
+        Ok(deliveries)
+    }
+
+    /// Claims every due delivery of the receiver that waits for the summary, so a summary
+    /// covers all of its items instead of those that made it into a claimed batch
+    pub async fn claim_summary_deliveries(
+        &self,
+        feed_name: &str,
+        receiver: &str,
+        action: DatabaseDeliveryAction,
+    ) -> Result<Vec<DatabaseDelivery>> {
+        self.cancel_abandoned_removed_deliveries(feed_name).await?;
+
+        let rows = sqlx::query(
+            "UPDATE deliveries d SET status = 'sending', updated_at = NOW() \
+            FROM feed_items i \
+            WHERE d.feed_item_id = i.id AND d.id IN ( \
+                SELECT d.id FROM deliveries d \
+                JOIN feed_items i ON i.id = d.feed_item_id \
+                WHERE i.feed_name = $1 AND d.receiver = $2 AND d.action = $3 \
+                AND d.next_attempt_at <= NOW() \
+                AND (d.status = 'pending' OR (d.status = 'sending' AND d.updated_at < NOW() - INTERVAL '5 minutes')) \
+                FOR UPDATE OF d SKIP LOCKED \
+            ) \
+            RETURNING d.id, d.receiver, d.attempts, d.action, d.message_ref, d.message_ref_type, \
+            i.feed_name, i.external_id, i.published_at, i.variables",
+        )
+        .bind(feed_name)
+        .bind(receiver)
+        .bind(action.as_str())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut deliveries = rows
+            .iter()
+            .map(delivery_from_row)
+            .collect::<Vec<DatabaseDelivery>>();
+
+        deliveries.sort_by_key(|d| d.item.published_at);
+
+        Ok(deliveries)
+    }
+
+    /// Cancels abandoned sends of items that were removed while they were being sent, claiming
+    /// them again would post the removed item
+    async fn cancel_abandoned_removed_deliveries(&self, feed_name: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE deliveries d SET status = 'cancelled', pending_action = NULL, updated_at = NOW() \
+            FROM feed_items i \
+            WHERE d.feed_item_id = i.id AND i.feed_name = $1 \
+            AND d.status = 'sending' AND d.updated_at < NOW() - INTERVAL '5 minutes' \
+            AND d.pending_action IN ('delete', 'retract') AND d.message_ref IS NULL",
+        )
+        .bind(feed_name)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the delivery as done, keeping the previous message reference when `None`, a new
+    /// reference is stored with the type of the receiver that sent it. The pending action of an
+    /// item that changed while it was sent is queued instead
+    pub async fn mark_delivery_delivered(
+        &self,
+        id: Uuid,
+        message_ref: Option<String>,
+        receiver_type: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE deliveries d SET \
+            status = CASE WHEN r.requeue THEN 'pending' ELSE 'delivered' END, \
+            action = CASE WHEN r.requeue THEN r.pending_action ELSE d.action END, \
+            attempts = CASE WHEN r.requeue THEN 0 ELSE d.attempts + 1 END, \
+            next_attempt_at = CASE WHEN r.requeue THEN NOW() ELSE d.next_attempt_at END, \
+            message_ref = COALESCE($2, d.message_ref), \
+            message_ref_type = CASE WHEN $2 IS NULL THEN d.message_ref_type ELSE $3 END, \
+            pending_action = NULL, last_error = NULL, updated_at = NOW() \
+            FROM ( \
+                SELECT id, pending_action, \
+                pending_action IS NOT NULL AND COALESCE($2, message_ref) IS NOT NULL AS requeue \
+                FROM deliveries WHERE id = $1 \
+            ) r \
+            WHERE d.id = r.id",
+        )
+        .bind(id)
+        .bind(message_ref)
+        .bind(receiver_type)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks a delivery that was rejected by a filter, it is kept to not reconsider the item
+    pub async fn mark_delivery_filtered(&self, id: Uuid) -> Result<()> {
+        sqlx::query(
+            "UPDATE deliveries SET status = 'filtered', pending_action = NULL, updated_at = NOW() \
+            WHERE id = $1",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Holds the delivery for a summary of the receiver, which is the summary other items are
+    /// already waiting for or a new one at `send_at`. An item removed while it was claimed is
+    /// cancelled instead
+    pub async fn queue_for_summary(
+        &self,
+        id: Uuid,
+        feed_name: &str,
+        receiver: &str,
+        action: DatabaseDeliveryAction,
+        send_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE deliveries SET action = $4, pending_action = NULL, updated_at = NOW(), \
+            status = CASE WHEN pending_action IN ('delete', 'retract') THEN 'cancelled' ELSE 'pending' END, \
+            next_attempt_at = COALESCE(( \
+                SELECT MIN(d.next_attempt_at) FROM deliveries d \
+                JOIN feed_items i ON i.id = d.feed_item_id \
+                WHERE i.feed_name = $2 AND d.receiver = $3 \
+                AND d.action = $4 AND d.status = 'pending' \
+            ), $5) \
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(feed_name)
+        .bind(receiver)
+        .bind(action.as_str())
+        .bind(send_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Puts the delivery back in the queue until `until` without counting an attempt. The
+    /// removal of an item that was removed while it was claimed takes its place, or cancels it
+    /// when nothing was sent yet
+    pub async fn defer_delivery(&self, id: Uuid, until: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            "UPDATE deliveries SET next_attempt_at = $2, pending_action = NULL, updated_at = NOW(), \
+            status = CASE WHEN pending_action IN ('delete', 'retract') AND message_ref IS NULL \
+                THEN 'cancelled' ELSE 'pending' END, \
+            action = CASE WHEN pending_action IN ('delete', 'retract') AND message_ref IS NOT NULL \
+                THEN pending_action ELSE action END \
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(until)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Amount of items sent on their own to the receiver since `since`, with the time the
+    /// oldest of them was sent
+    pub async fn count_sent_deliveries(
+        &self,
+        feed_name: &str,
+        receiver: &str,
+        since: DateTime<Utc>,
+    ) -> Result<(i64, Option<DateTime<Utc>>)> {
+        let row = sqlx::query(
+            "SELECT COUNT(*), MIN(d.updated_at) FROM deliveries d \
+            JOIN feed_items i ON i.id = d.feed_item_id \
+            WHERE i.feed_name = $1 AND d.receiver = $2 \
+            AND d.status = 'delivered' AND d.action = 'send' AND d.updated_at > $3",
+        )
+        .bind(feed_name)
+        .bind(receiver)
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await?;
 
-        Ok(rows.iter().map(|r| r.get(0)).collect())
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /// Records a failed attempt, the delivery is retried at `next_attempt_at` or
+    /// moved to the dead-letter state when `next_attempt_at` is `None`. An item removed while
+    /// it was claimed is cancelled when nothing was sent yet, or its removal is queued instead
+    pub async fn mark_delivery_failed(
+        &self,
+        id: Uuid,
+        error: &str,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE deliveries SET last_error = $3, pending_action = NULL, updated_at = NOW(), \
+            status = CASE \
+                WHEN pending_action IS NULL OR pending_action = 'update' THEN $2 \
+                WHEN message_ref IS NULL THEN 'cancelled' \
+                ELSE 'pending' END, \
+            action = CASE WHEN pending_action IN ('delete', 'retract') AND message_ref IS NOT NULL \
+                THEN pending_action ELSE action END, \
+            attempts = CASE WHEN pending_action IN ('delete', 'retract') AND message_ref IS NOT NULL \
+                THEN 0 ELSE attempts + 1 END, \
+            next_attempt_at = CASE WHEN pending_action IN ('delete', 'retract') AND message_ref IS NOT NULL \
+                THEN NOW() ELSE COALESCE($4, next_attempt_at) END \
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(match next_attempt_at {
+            Some(_) => "pending",
+            None => "dead",
+        })
+        .bind(error)
+        .bind(next_attempt_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn select_feed_state(&self, feed_name: &str) -> Result<DatabaseFeedState> {
+        // feeds with items from before the state was tracked count as initialized
+        let row = sqlx::query(
+            "SELECT s.etag, s.last_modified, \
+            s.initialized_at IS NOT NULL OR EXISTS (SELECT 1 FROM feed_items WHERE feed_name = f.feed_name) \
+            FROM (SELECT $1::VARCHAR AS feed_name) f \
+            LEFT JOIN feed_state s ON s.feed_name = f.feed_name",
+        )
+        .bind(feed_name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(DatabaseFeedState {
+            etag: row.get(0),
+            last_modified: row.get(1),
+            initialized: row.get(2),
+        })
+    }
+
+    /// Stores the cache validators and marks the feed as initialized
+    pub async fn update_feed_state(
+        &self,
+        feed_name: &str,
+        state: &DatabaseFeedState,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO feed_state (feed_name, etag, last_modified, initialized_at) \
+            VALUES ($1, $2, $3, NOW()) \
+            ON CONFLICT (feed_name) DO UPDATE SET etag = $2, last_modified = $3, \
+            initialized_at = COALESCE(feed_state.initialized_at, NOW()), updated_at = NOW()",
+        )
+        .bind(feed_name)
+        .bind(&state.etag)
+        .bind(&state.last_modified)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Delivery from a row returned by one of the claim queries
+fn delivery_from_row(r: &PgRow) -> DatabaseDelivery {
+    let action: String = r.get(3);
+    let published_at: DateTime<Utc> = r.get(8);
+    let variables: Option<Value> = r.get(9);
+    DatabaseDelivery {
+        id: r.get(0),
+        receiver: r.get(1),
+        attempts: r.get(2),
+        action: match action.as_str() {
+            "update" => DatabaseDeliveryAction::Update,
+            "delete" => DatabaseDeliveryAction::Delete,
+            "retract" => DatabaseDeliveryAction::Retract,
+            "digest" => DatabaseDeliveryAction::Digest,
+            "overflow" => DatabaseDeliveryAction::Overflow,
+            _ => DatabaseDeliveryAction::Send,
+        },
+        message_ref: r.get(4),
+        message_ref_type: r.get(5),
+        item: DatabaseFeedItem {
+            feed_name: r.get(6),
+            external_id: r.get(7),
+            published_at: published_at.fixed_offset(),
+            variables: serde_json::from_value(variables.unwrap_or_default()).unwrap_or_default(),
+        },
+    }
+}
+
+/// Item of a test feed published at a fixed time, linking to a page named after its id
+#[cfg(test)]
+pub fn test_item(external_id: &str, title: &str) -> DatabaseFeedItem {
+    DatabaseFeedItem {
+        feed_name: String::from("my feed"),
+        external_id: external_id.to_owned(),
+        published_at: DateTime::parse_from_rfc3339("2024-01-01T10:00:00+00:00").unwrap(),
+        variables: BTreeMap::from([
+            (String::from("title"), title.to_owned()),
+            (
+                String::from("link"),
+                format!("https://example.org/{}", external_id),
+            ),
+        ]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> DatabaseFeedItem {
+        let mut item = test_item("1", "Fish_and *chips*");
+        item.variables.insert(
+            String::from("description"),
+            String::from("<p>Hello &amp; <b>world</b></p>"),
+        );
+        item
+    }
+
+    #[test]
+    fn substitutes_variants() {
+        let item = item();
+
+        assert_eq!(item.sub("$title"), "Fish_and *chips*");
+        assert_eq!(item.sub("$title_escaped"), "Fish\\_and \\*chips\\*");
+        assert_eq!(item.sub("${description_text}!"), "Hello & world!");
+        assert_eq!(item.sub("$description_md"), "Hello & **world**");
+        assert_eq!(item.variable("missing_escaped"), None);
+    }
+
+    #[test]
+    fn keeps_input_with_missing_variables() {
+        let item = item();
+
+        assert_eq!(item.sub("$title $missing"), "$title $missing");
+        assert_eq!(item.try_sub("$missing"), None);
+    }
+
+    #[test]
+    fn escapes_every_variable() {
+        let item = item();
+        let upper = |s: &str| s.to_uppercase();
+
+        assert_eq!(
+            item.sub_escaped("*$title*: ${description_text}", upper),
+            "*FISH_AND *CHIPS**: HELLO & WORLD"
+        );
     }
 }
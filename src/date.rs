@@ -0,0 +1,183 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
+
+/// Formats with an offset tried after RFC 2822 and RFC 3339, for feeds with sloppy dates
+const OFFSET_FORMATS: [&str; 6] = [
+    "%a, %d %b %Y %H:%M %z",
+    "%d %b %Y %H:%M:%S %z",
+    "%d %b %Y %H:%M %z",
+    "%Y-%m-%d %H:%M:%S %z",
+    "%Y-%m-%d %H:%M:%S%.f%z",
+    "%Y-%m-%dT%H:%M%z",
+];
+
+/// Formats without an offset, these are assumed to be in UTC
+const NAIVE_FORMATS: [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%a, %d %b %Y %H:%M:%S",
+];
+
+/// Named zones that RFC 2822 does not know about, mapped to their offset
+const NAMED_ZONES: [(&str, &str); 10] = [
+    ("CEST", "+0200"),
+    ("CET", "+0100"),
+    ("MEST", "+0200"),
+    ("MET", "+0100"),
+    ("BST", "+0100"),
+    ("EEST", "+0300"),
+    ("EET", "+0200"),
+    ("WEST", "+0100"),
+    ("WET", "+0000"),
+    ("UTC", "+0000"),
+];
+
+/// Parses a date in one of the many formats found in feeds, the custom format is tried first
+pub fn parse_datetime(input: &str, custom_format: Option<&str>) -> Option<DateTime<FixedOffset>> {
+    let input = input.trim();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    if let Some(format) = custom_format {
+        if let Some(datetime) = parse_with_format(input, format) {
+            return Some(datetime);
+        }
+    }
+
+    if let Ok(datetime) = DateTime::parse_from_rfc2822(input) {
+        return Some(datetime);
+    }
+
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(input) {
+        return Some(datetime);
+    }
+
+    let input = replace_named_zone(input);
+
+    if let Ok(datetime) = DateTime::parse_from_rfc2822(&input) {
+        return Some(datetime);
+    }
+
+    OFFSET_FORMATS
+        .iter()
+        .chain(NAIVE_FORMATS.iter())
+        .chain(["%Y-%m-%d"].iter())
+        .find_map(|format| parse_with_format(&input, format))
+}
+
+fn parse_with_format(input: &str, format: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(datetime) = DateTime::parse_from_str(input, format) {
+        return Some(datetime);
+    }
+
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(input, format) {
+        return Some(datetime.and_utc().fixed_offset());
+    }
+
+    NaiveDate::parse_from_str(input, format)
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.and_utc().fixed_offset())
+}
+
+fn replace_named_zone(input: &str) -> String {
+    let Some((rest, zone)) = input.rsplit_once(' ') else {
+        return input.to_owned();
+    };
+
+    match NAMED_ZONES.iter().find(|(name, _)| *name == zone) {
+        Some((_, offset)) => format!("{} {}", rest, offset),
+        None => input.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Option<String> {
+        parse_datetime(input, None).map(|d| d.to_rfc3339())
+    }
+
+    #[test]
+    fn parses_rfc2822() {
+        assert_eq!(
+            parse("Mon, 01 Jan 2024 10:00:00 +0000").as_deref(),
+            Some("2024-01-01T10:00:00+00:00")
+        );
+        assert_eq!(
+            parse("Mon, 1 Jan 2024 10:00:00 GMT").as_deref(),
+            Some("2024-01-01T10:00:00+00:00")
+        );
+        assert_eq!(
+            parse("  Mon, 01 Jan 2024 10:00:00 -0500\n").as_deref(),
+            Some("2024-01-01T10:00:00-05:00")
+        );
+    }
+
+    #[test]
+    fn parses_rfc3339() {
+        assert_eq!(
+            parse("2024-01-01T10:00:00Z").as_deref(),
+            Some("2024-01-01T10:00:00+00:00")
+        );
+        assert_eq!(
+            parse("2024-01-01T10:00:00.250+01:00").as_deref(),
+            Some("2024-01-01T10:00:00.250+01:00")
+        );
+    }
+
+    #[test]
+    fn parses_named_zones() {
+        assert_eq!(
+            parse("Mon, 01 Jul 2024 10:00:00 CEST").as_deref(),
+            Some("2024-07-01T10:00:00+02:00")
+        );
+        assert_eq!(
+            parse("Mon, 01 Jan 2024 10:00:00 UTC").as_deref(),
+            Some("2024-01-01T10:00:00+00:00")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_sloppy_formats() {
+        assert_eq!(
+            parse("Mon, 01 Jan 2024 10:00 +0100").as_deref(),
+            Some("2024-01-01T10:00:00+01:00")
+        );
+        assert_eq!(
+            parse("2024-01-01 10:00:00 +0100").as_deref(),
+            Some("2024-01-01T10:00:00+01:00")
+        );
+        assert_eq!(
+            parse("2024-01-01T10:00:00").as_deref(),
+            Some("2024-01-01T10:00:00+00:00")
+        );
+        assert_eq!(
+            parse("2024-01-01").as_deref(),
+            Some("2024-01-01T00:00:00+00:00")
+        );
+    }
+
+    #[test]
+    fn tries_the_custom_format_first() {
+        assert_eq!(
+            parse_datetime("02/01/2024 10:00", Some("%d/%m/%Y %H:%M")).map(|d| d.to_rfc3339()),
+            Some(String::from("2024-01-02T10:00:00+00:00"))
+        );
+        assert_eq!(
+            parse_datetime("2024-01-02", Some("%d/%m/%Y")).map(|d| d.to_rfc3339()),
+            Some(String::from("2024-01-02T00:00:00+00:00"))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_dates() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("   "), None);
+        assert_eq!(parse("yesterday"), None);
+        assert_eq!(parse("2024-13-45"), None);
+    }
+}
@@ -1,18 +1,32 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+};
 
 use anyhow::{anyhow, Result};
-use atom_syndication::{Entry, Feed as AtomFeed};
-use chrono::{DateTime, FixedOffset};
+use atom_syndication::{extension::Extension as AtomExtension, Entry, Feed as AtomFeed};
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
 use chrono_tz::Tz;
+use cron::Schedule;
 use fancy_regex::Regex;
-use log::debug;
-use reqwest::Method;
+use log::{debug, warn};
+use reqwest::{header::HeaderName, Method, Response, StatusCode};
 use rss::{extension::Extension, Channel, Guid, Item};
+use sha2::{Digest, Sha256};
 
 use crate::{
-    config::{ConfigFeed, ConfigFeedReceiver, ConfigFeedReceiverType},
-    database::{Database, DatabaseFeedItem},
-    receivers::{discord::DiscordReceiver, Receivable},
+    config::{
+        ConfigFeed, ConfigFeedFormat, ConfigFeedInitialSync, ConfigFeedReceiver,
+        ConfigFeedReceiverDigest, ConfigFeedReceiverRemoval,
+    },
+    database::{
+        Database, DatabaseDelivery, DatabaseDeliveryAction, DatabaseFeedItem, DatabaseFeedState,
+    },
+    date::parse_datetime,
+    filter::Filter,
+    json_feed::{JsonFeed, JsonFeedItem},
+    quiet_hours::QuietHours,
+    receivers::{PermanentError, Receivable, Receiver},
 };
 
 #[derive(Clone)]
@@ -20,128 +34,1033 @@ pub struct Feed {
     pub id: String,
     url: String,
     user_agent: Option<String>,
-    receivers: Vec<ConfigFeedReceiver>,
+    receivers: Vec<FeedReceiver>,
     regex: Option<Regex>,
-    atom: Option<bool>,
+    /// Explicit format, detected from the response when `None`
+    format: Option<ConfigFeedFormat>,
+    max_delivery_attempts: u32,
+    date_format: Option<String>,
+    initial_sync: ConfigFeedInitialSync,
+    initial_sync_count: usize,
+    filter: Option<Filter>,
+    removal_window: TimeDelta,
+}
+
+/// Configured receiver with the parts of its config that are parsed up front
+#[derive(Clone)]
+struct FeedReceiver {
+    key: String,
+    config: ConfigFeedReceiver,
+    filter: Option<Filter>,
+    quiet_hours: Option<QuietHours>,
+    digest_schedule: Option<DigestSchedule>,
+    target: Receiver,
+}
+
+/// When the digest of a receiver is sent
+#[derive(Clone)]
+enum DigestSchedule {
+    Cron(Box<Schedule>),
+    Interval(TimeDelta),
 }
 
+/// Amount of deliveries claimed from the queue per worker run
+const DELIVERY_BATCH_SIZE: i64 = 50;
+const DEFAULT_MAX_DELIVERY_ATTEMPTS: u32 = 10;
+const DEFAULT_REMOVAL_WINDOW: u64 = 24 * 60 * 60;
+const BACKOFF_BASE_SECONDS: i64 = 30;
+const BACKOFF_MAX_SECONDS: i64 = 6 * 60 * 60;
+const DEFAULT_OVERFLOW_TITLE: &str = "And $count more items";
+
 impl Feed {
-    pub fn from_config(config: ConfigFeed) -> Self {
-        Feed {
+    pub fn from_config(config: ConfigFeed) -> Result<Self> {
+        let mut receivers: Vec<FeedReceiver> = Vec::new();
+
+        for receiver in config.receivers {
+            let key = receiver_key(&receiver);
+
+            if receivers.iter().any(|r| r.key == key) {
+                return Err(anyhow!(
+                    "receivers of feed {} share the key {}, give them an id",
+                    config.id,
+                    key
+                ));
+            }
+
+            let filter = receiver
+                .filter
+                .as_ref()
+                .map(Filter::from_config)
+                .transpose()
+                .map_err(|e| anyhow!("receiver {} of feed {}: {}", key, config.id, e))?;
+
+            let quiet_hours = receiver
+                .quiet_hours
+                .as_ref()
+                .map(QuietHours::from_config)
+                .transpose()
+                .map_err(|e| anyhow!("receiver {} of feed {}: {}", key, config.id, e))?;
+
+            let digest_schedule = receiver
+                .digest
+                .as_ref()
+                .map(DigestSchedule::from_config)
+                .transpose()
+                .map_err(|e| anyhow!("receiver {} of feed {}: {}", key, config.id, e))?;
+
+            let target = Receiver::from_config(&receiver)
+                .map_err(|e| anyhow!("receiver {} of feed {}: {}", key, config.id, e))?;
+
+            // these would dead-letter every item or do nothing at all
+            if digest_schedule.is_some() && !target.supports_digests() {
+                return Err(anyhow!(
+                    "receiver {} of feed {}: {} receivers don't support digests",
+                    key,
+                    config.id,
+                    receiver.receiver_type.name()
+                ));
+            }
+
+            if !target.supports_removal(receiver.on_removed) {
+                return Err(anyhow!(
+                    "receiver {} of feed {}: {} receivers can't {} the messages of removed items",
+                    key,
+                    config.id,
+                    receiver.receiver_type.name(),
+                    format!("{:?}", receiver.on_removed).to_lowercase()
+                ));
+            }
+
+            receivers.push(FeedReceiver {
+                key,
+                config: receiver,
+                filter,
+                quiet_hours,
+                digest_schedule,
+                target,
+            });
+        }
+
+        Ok(Feed {
+            filter: config
+                .filter
+                .as_ref()
+                .map(Filter::from_config)
+                .transpose()
+                .map_err(|e| anyhow!("feed {}: {}", config.id, e))?,
+            regex: config
+                .guid_regex
+                .map(|re| {
+                    Regex::new(&re)
+                        .map_err(|e| anyhow!("invalid guid_regex of feed {}: {}", config.id, e))
+                })
+                .transpose()?,
             id: config.id,
             url: config.rss_url,
             user_agent: config.user_agent,
-            receivers: config.receivers,
-            regex: config
-                .guid_regex
-                .map(|re| Regex::new(&re).expect("Invalid regex")),
-            atom: config.atom,
-        }
+            receivers,
+            format: config.format.or(match config.atom {
+                Some(true) => Some(ConfigFeedFormat::Atom),
+                Some(false) => Some(ConfigFeedFormat::Rss),
+                None => None,
+            }),
+            max_delivery_attempts: config
+                .max_delivery_attempts
+                .unwrap_or(DEFAULT_MAX_DELIVERY_ATTEMPTS),
+            date_format: config.date_format,
+            initial_sync: config.initial_sync.unwrap_or(ConfigFeedInitialSync::All),
+            initial_sync_count: config.initial_sync_count.unwrap_or(1),
+            removal_window: TimeDelta::seconds(
+                config.removal_window.unwrap_or(DEFAULT_REMOVAL_WINDOW) as i64,
+            ),
+        })
     }
 
     pub async fn process(&self, database: &Database) -> Result<()> {
-        let mut items = self.fetch_and_parse_feed().await?;
-
         debug!("Fetching feed {} {}", self.id, self.url);
 
+        let state = database.select_feed_state(&self.id).await?;
+
+        let Some((mut items, new_state)) = self.fetch_and_parse_feed(&state).await? else {
+            debug!("Feed {} not modified since last poll", self.id);
+            return self.deliver(database).await;
+        };
+
         items.sort_by_key(|i| i.published_at);
 
+        // a batch can't update the same row twice, some feeds repeat items
+        let mut seen = HashSet::new();
+        items.retain(|i| seen.insert(i.external_id.clone()));
+
         debug!("Received {} items from feed {}", items.len(), self.id);
 
-        let new_item_ids = database.insert_and_select_feed_items(&items).await?;
+        let receiver_keys: Vec<String> = self.receivers.iter().map(|r| r.key.clone()).collect();
 
-        for item in items
-            .into_iter()
-            .filter(|i| new_item_ids.contains(&i.external_id))
-        {
-            debug!("Sending notification for new item {}", item.external_id);
-
-            for receiver in &self.receivers {
-                match receiver.receiver_type {
-                    ConfigFeedReceiverType::Discord => {
-                        DiscordReceiver::new(&receiver.discord)
-                            .send_item(&item)
-                            .await?
+        let edit_receiver_keys: Vec<String> = self
+            .receivers
+            .iter()
+            .filter(|r| r.config.edits)
+            .map(|r| r.key.clone())
+            .collect();
+
+        // on the first run only the newest items are delivered, the rest is recorded as seen
+        let skip = match (state.initialized, self.initial_sync) {
+            (false, ConfigFeedInitialSync::Skip) => items.len(),
+            (false, ConfigFeedInitialSync::LatestN) => {
+                items.len().saturating_sub(self.initial_sync_count)
+            }
+            _ => 0,
+        };
+
+        if skip > 0 {
+            debug!(
+                "Initial sync of feed {}, recording {} items without delivering them",
+                self.id, skip
+            );
+            database
+                .insert_feed_items_and_enqueue(&items[..skip], &[], &[])
+                .await?;
+        }
+
+        let (new_item_ids, updated_item_ids) = if items.len() > skip {
+            database
+                .insert_feed_items_and_enqueue(&items[skip..], &receiver_keys, &edit_receiver_keys)
+                .await?
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        if !items.is_empty() {
+            self.mark_removed_items(database, &items).await?;
+        }
+
+        // only remember the validators once the items are stored, otherwise a
+        // failed insert would be hidden behind a 304 on the next poll
+        database.update_feed_state(&self.id, &new_state).await?;
+
+        debug!(
+            "Queued {} new and {} updated items from feed {}",
+            new_item_ids.len(),
+            updated_item_ids.len(),
+            self.id
+        );
+
+        self.deliver(database).await
+    }
+
+    async fn mark_removed_items(
+        &self,
+        database: &Database,
+        items: &[DatabaseFeedItem],
+    ) -> Result<()> {
+        let receivers_for = |removal: ConfigFeedReceiverRemoval| -> Vec<String> {
+            self.receivers
+                .iter()
+                .filter(|r| r.config.on_removed == removal)
+                .map(|r| r.key.clone())
+                .collect()
+        };
+
+        let present: Vec<String> = items.iter().map(|i| i.external_id.clone()).collect();
+
+        let removed = database
+            .mark_removed_feed_items(
+                &self.id,
+                &present,
+                self.removal_window,
+                &receivers_for(ConfigFeedReceiverRemoval::Delete),
+                &receivers_for(ConfigFeedReceiverRemoval::Retract),
+            )
+            .await?;
+
+        if !removed.is_empty() {
+            debug!(
+                "Items {} were removed from feed {}",
+                removed.join(", "),
+                self.id
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Drains the due deliveries of this feed from the queue
+    pub async fn deliver(&self, database: &Database) -> Result<()> {
+        let deliveries = database
+            .claim_deliveries(&self.id, DELIVERY_BATCH_SIZE)
+            .await?;
+
+        // new items for receivers that batch are sent together afterwards, as are the items
+        // whose digest or overflow summary is due
+        let mut batches: BTreeMap<String, Vec<DatabaseDelivery>> = BTreeMap::new();
+        let mut digests: BTreeMap<String, Vec<DatabaseDelivery>> = BTreeMap::new();
+        let mut overflows: BTreeMap<String, Vec<DatabaseDelivery>> = BTreeMap::new();
+
+        for delivery in deliveries {
+            let receiver = match self.receiver_for(&delivery) {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    self.delivery_failed(database, &delivery, &e).await?;
+                    continue;
+                }
+            };
+
+            if delivery.action == DatabaseDeliveryAction::Send {
+                match self.accepts(receiver, &delivery) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        database.mark_delivery_filtered(delivery.id).await?;
+                        continue;
+                    }
+                    Err(e) => {
+                        self.delivery_failed(database, &delivery, &e).await?;
+                        continue;
+                    }
+                }
+            }
+
+            match self.quiet_until(receiver, &delivery) {
+                Ok(Some(until)) => {
+                    debug!(
+                        "Holding item {} for receiver {} until {}",
+                        delivery.item.external_id, delivery.receiver, until
+                    );
+                    database.defer_delivery(delivery.id, until).await?;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.delivery_failed(database, &delivery, &e).await?;
+                    continue;
+                }
+            }
+
+            match (delivery.action, &receiver.digest_schedule) {
+                (DatabaseDeliveryAction::Send, Some(schedule)) => {
+                    match schedule.next() {
+                        Ok(next_digest_at) => {
+                            database
+                                .queue_for_summary(
+                                    delivery.id,
+                                    &self.id,
+                                    &delivery.receiver,
+                                    DatabaseDeliveryAction::Digest,
+                                    next_digest_at,
+                                )
+                                .await?
+                        }
+                        Err(e) => self.delivery_failed(database, &delivery, &e).await?,
                     }
+                    continue;
                 }
+                (DatabaseDeliveryAction::Digest, Some(_)) => {
+                    digests
+                        .entry(delivery.receiver.clone())
+                        .or_default()
+                        .push(delivery);
+                    continue;
+                }
+                (DatabaseDeliveryAction::Overflow, _) if receiver.config.throttle.is_some() => {
+                    overflows
+                        .entry(delivery.receiver.clone())
+                        .or_default()
+                        .push(delivery);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let batch = delivery.action == DatabaseDeliveryAction::Send
+                && receiver.target.batch_size().is_some();
+
+            if let (DatabaseDeliveryAction::Send, Some(throttle)) =
+                (delivery.action, &receiver.config.throttle)
+            {
+                // batched items are only marked as delivered after the loop
+                let pending = batches.get(&delivery.receiver).map_or(0, |b| b.len());
+                let window = TimeDelta::seconds(throttle.window as i64);
+                let (sent, oldest) = database
+                    .count_sent_deliveries(&self.id, &delivery.receiver, Utc::now() - window)
+                    .await?;
+
+                if sent as usize + pending >= throttle.max {
+                    let release_at = oldest.unwrap_or(Utc::now()) + window;
+                    debug!(
+                        "Receiver {} is throttled, item {} is summarized at {}",
+                        delivery.receiver, delivery.item.external_id, release_at
+                    );
+                    database
+                        .queue_for_summary(
+                            delivery.id,
+                            &self.id,
+                            &delivery.receiver,
+                            DatabaseDeliveryAction::Overflow,
+                            release_at,
+                        )
+                        .await?;
+                    continue;
+                }
+            }
+
+            if batch {
+                batches
+                    .entry(delivery.receiver.clone())
+                    .or_default()
+                    .push(delivery);
+                continue;
+            }
+
+            match self.deliver_item(receiver, &delivery).await {
+                Ok(message_ref) => {
+                    database
+                        .mark_delivery_delivered(
+                            delivery.id,
+                            message_ref,
+                            receiver.config.receiver_type.name(),
+                        )
+                        .await?
+                }
+                Err(e) => self.delivery_failed(database, &delivery, &e).await?,
+            }
+        }
+
+        for (key, deliveries) in batches {
+            let Some(receiver) = self.find_receiver(&key) else {
+                continue;
+            };
+
+            debug!(
+                "Sending batch of {} items to receiver {}",
+                deliveries.len(),
+                key
+            );
+
+            let items: Vec<DatabaseFeedItem> = deliveries.iter().map(|d| d.item.clone()).collect();
+            let mut sent = 0;
+
+            while sent < items.len() {
+                match receiver.target.send_batch(&items[sent..]).await {
+                    Ok(count) => {
+                        let count = count.clamp(1, items.len() - sent);
+                        for delivery in &deliveries[sent..sent + count] {
+                            database
+                                .mark_delivery_delivered(
+                                    delivery.id,
+                                    None,
+                                    receiver.config.receiver_type.name(),
+                                )
+                                .await?;
+                        }
+                        sent += count;
+                    }
+                    Err(e) => {
+                        for delivery in &deliveries[sent..] {
+                            self.delivery_failed(database, delivery, &e).await?;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        for (key, deliveries) in digests {
+            let Some(digest) = self
+                .find_receiver(&key)
+                .and_then(|r| r.config.digest.clone())
+            else {
+                continue;
+            };
+
+            let deliveries = self
+                .claim_summary(database, &key, DatabaseDeliveryAction::Digest, deliveries)
+                .await?;
+
+            debug!(
+                "Sending digest of {} items to receiver {}",
+                deliveries.len(),
+                key
+            );
+
+            self.send_summary(database, &key, &digest, &deliveries)
+                .await?;
+        }
+
+        for (key, deliveries) in overflows {
+            let Some(throttle) = self
+                .find_receiver(&key)
+                .and_then(|r| r.config.throttle.clone())
+            else {
+                continue;
+            };
+
+            let deliveries = self
+                .claim_summary(database, &key, DatabaseDeliveryAction::Overflow, deliveries)
+                .await?;
+
+            debug!(
+                "Sending overflow summary of {} items to receiver {}",
+                deliveries.len(),
+                key
+            );
+
+            // the overflow is rendered like a digest of the items that were held back
+            let summary = ConfigFeedReceiverDigest {
+                schedule: None,
+                interval: None,
+                title: Some(
+                    throttle
+                        .overflow
+                        .unwrap_or(String::from(DEFAULT_OVERFLOW_TITLE)),
+                ),
+                line: throttle.line,
+            };
+
+            self.send_summary(database, &key, &summary, &deliveries)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn find_receiver(&self, key: &str) -> Option<&FeedReceiver> {
+        self.receivers.iter().find(|r| r.key == key)
+    }
+
+    /// Receiver of the delivery, a message sent by a receiver of another type can't be edited
+    /// or deleted by this one
+    fn receiver_for(&self, delivery: &DatabaseDelivery) -> Result<&FeedReceiver> {
+        let receiver = self
+            .find_receiver(&delivery.receiver)
+            .ok_or(PermanentError(format!(
+                "receiver {} is no longer configured",
+                delivery.receiver
+            )))?;
+
+        match &delivery.message_ref_type {
+            Some(message_ref_type) if message_ref_type != receiver.config.receiver_type.name() => {
+                Err(PermanentError(format!(
+                    "message for receiver {} was sent by the {} type, it is now configured as {}",
+                    delivery.receiver,
+                    message_ref_type,
+                    receiver.config.receiver_type.name()
+                ))
+                .into())
+            }
+            _ => Ok(receiver),
+        }
+    }
+
+    /// Whether the item passes the feed and receiver filters
+    fn accepts(&self, receiver: &FeedReceiver, delivery: &DatabaseDelivery) -> Result<bool> {
+        for filter in [&self.filter, &receiver.filter].into_iter().flatten() {
+            if !filter.matches(&delivery.item)? {
+                debug!(
+                    "Item {} filtered for receiver {}",
+                    delivery.item.external_id, delivery.receiver
+                );
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// End of the quiet hours of the receiver when the delivery has to wait for it, edits and
+    /// urgent items are never held
+    fn quiet_until(
+        &self,
+        receiver: &FeedReceiver,
+        delivery: &DatabaseDelivery,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let Some(quiet_hours) = &receiver.quiet_hours else {
+            return Ok(None);
+        };
+
+        if !matches!(
+            delivery.action,
+            DatabaseDeliveryAction::Send
+                | DatabaseDeliveryAction::Digest
+                | DatabaseDeliveryAction::Overflow
+        ) {
+            return Ok(None);
+        }
+
+        if let Some(urgent) = &quiet_hours.urgent {
+            if urgent.matches(&delivery.item)? {
+                return Ok(None);
+            }
+        }
+
+        quiet_hours.until(Utc::now())
+    }
+
+    /// Adds the due deliveries of the summary that were not in the claimed batch, except those
+    /// held by quiet hours
+    async fn claim_summary(
+        &self,
+        database: &Database,
+        key: &str,
+        action: DatabaseDeliveryAction,
+        mut deliveries: Vec<DatabaseDelivery>,
+    ) -> Result<Vec<DatabaseDelivery>> {
+        let Some(receiver) = self.find_receiver(key) else {
+            return Ok(deliveries);
+        };
+
+        for delivery in database
+            .claim_summary_deliveries(&self.id, key, action)
+            .await?
+        {
+            match self.quiet_until(receiver, &delivery) {
+                Ok(Some(until)) => database.defer_delivery(delivery.id, until).await?,
+                Ok(None) => deliveries.push(delivery),
+                Err(e) => self.delivery_failed(database, &delivery, &e).await?,
+            }
+        }
+
+        deliveries.sort_by_key(|d| d.item.published_at);
+
+        Ok(deliveries)
+    }
+
+    /// Sends a single summary of the deliveries to the receiver, the items of messages that
+    /// were posted before a failure are marked as delivered
+    async fn send_summary(
+        &self,
+        database: &Database,
+        key: &str,
+        summary: &ConfigFeedReceiverDigest,
+        deliveries: &[DatabaseDelivery],
+    ) -> Result<()> {
+        let Some(receiver) = self.find_receiver(key) else {
+            return Ok(());
+        };
+
+        let items: Vec<DatabaseFeedItem> = deliveries.iter().map(|d| d.item.clone()).collect();
+        let mut sent = 0;
+
+        let result = receiver
+            .target
+            .send_digest(summary, &items, &mut sent)
+            .await;
+
+        let sent = match result {
+            Ok(()) => deliveries.len(),
+            Err(_) => sent.min(deliveries.len()),
+        };
+
+        for delivery in &deliveries[..sent] {
+            database
+                .mark_delivery_delivered(delivery.id, None, receiver.config.receiver_type.name())
+                .await?;
+        }
+
+        if let Err(e) = result {
+            for delivery in &deliveries[sent..] {
+                self.delivery_failed(database, delivery, &e).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn fetch_and_parse_feed(&self) -> Result<Vec<DatabaseFeedItem>> {
+    /// Schedules a retry with backoff, or gives up when the error is permanent or the delivery
+    /// ran out of attempts
+    async fn delivery_failed(
+        &self,
+        database: &Database,
+        delivery: &DatabaseDelivery,
+        e: &anyhow::Error,
+    ) -> Result<()> {
+        let attempts = delivery.attempts as u32 + 1;
+
+        if e.is::<PermanentError>() || attempts >= self.max_delivery_attempts {
+            warn!(
+                "Giving up on item {} for receiver {} of feed {} after {} attempts: {}",
+                delivery.item.external_id, delivery.receiver, self.id, attempts, e
+            );
+            database
+                .mark_delivery_failed(delivery.id, &e.to_string(), None)
+                .await
+        } else {
+            let backoff = backoff(attempts);
+            warn!(
+                "Failed to deliver item {} to receiver {} of feed {}, retrying in {}s: {}",
+                delivery.item.external_id,
+                delivery.receiver,
+                self.id,
+                backoff.num_seconds(),
+                e
+            );
+            database
+                .mark_delivery_failed(delivery.id, &e.to_string(), Some(Utc::now() + backoff))
+                .await
+        }
+    }
+
+    /// Sends the item, or edits the previously sent message when the item was updated. Returns
+    /// a reference to the sent message if the receiver returned one
+    async fn deliver_item(
+        &self,
+        receiver: &FeedReceiver,
+        delivery: &DatabaseDelivery,
+    ) -> Result<Option<String>> {
+        let target = &receiver.target;
+
+        if let Some(message_ref) = &delivery.message_ref {
+            let done = match delivery.action {
+                DatabaseDeliveryAction::Send
+                | DatabaseDeliveryAction::Digest
+                | DatabaseDeliveryAction::Overflow => false,
+                DatabaseDeliveryAction::Update => {
+                    debug!(
+                        "Updating notification for item {} on receiver {}",
+                        delivery.item.external_id, delivery.receiver
+                    );
+                    target.update_item(&delivery.item, message_ref).await?;
+                    true
+                }
+                DatabaseDeliveryAction::Retract => {
+                    debug!(
+                        "Retracting notification for item {} on receiver {}",
+                        delivery.item.external_id, delivery.receiver
+                    );
+                    target.retract_item(&delivery.item, message_ref).await?;
+                    true
+                }
+                DatabaseDeliveryAction::Delete => {
+                    debug!(
+                        "Deleting notification for item {} on receiver {}",
+                        delivery.item.external_id, delivery.receiver
+                    );
+                    target.delete_item(message_ref).await?;
+                    true
+                }
+            };
+
+            if done {
+                return Ok(None);
+            }
+        }
+
+        debug!(
+            "Sending notification for item {} to receiver {}",
+            delivery.item.external_id, delivery.receiver
+        );
+        target.send_item(&delivery.item).await
+    }
+
+    /// Fetches the feed, returns `None` when the server answered `304 Not Modified`
+    async fn fetch_and_parse_feed(
+        &self,
+        state: &DatabaseFeedState,
+    ) -> Result<Option<(Vec<DatabaseFeedItem>, DatabaseFeedState)>> {
         let client = reqwest::Client::new();
 
-        let req = match &self.user_agent {
+        let mut req = match &self.user_agent {
             Some(agent) => client
                 .request(Method::GET, &self.url)
                 .header(reqwest::header::USER_AGENT, agent),
             None => client.request(Method::GET, &self.url),
         };
 
+        if let Some(etag) = &state.etag {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
+        if let Some(last_modified) = &state.last_modified {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+
         let resp = req.send().await?;
 
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
         if resp.status().as_u16() != 200 {
             return Err(anyhow!("unexpected statuscode {}", resp.status()));
         }
 
-        // if atom (default is RSS)
-        if self.atom.unwrap_or(false) {
-            // parse as Atom
-            debug!("Parsing feed {} as Atom", self.id);
+        let new_state = DatabaseFeedState {
+            etag: header_to_string(&resp, reqwest::header::ETAG),
+            last_modified: header_to_string(&resp, reqwest::header::LAST_MODIFIED),
+            initialized: true,
+        };
 
-            let content = resp.text().await?;
+        let content_type = header_to_string(&resp, reqwest::header::CONTENT_TYPE);
+        let content = resp.bytes().await?;
 
-            // tenderned bodges
+        let items = self.parse_feed(&content, content_type.as_deref())?;
 
-            // remove atom namespaces
-            let content = content.replace("<atom:", "<");
-            let content = content.replace("</atom:", "</");
+        Ok(Some((items, new_state)))
+    }
 
-            // remove xml declaration
-            let content = content.replace(
-                "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>",
-                "",
-            );
+    fn parse_feed(
+        &self,
+        content: &[u8],
+        content_type: Option<&str>,
+    ) -> Result<Vec<DatabaseFeedItem>> {
+        let format = match self.format {
+            Some(format) => format,
+            None => {
+                let format = detect_format(content, content_type);
+                debug!(
+                    "Detected format {:?} for feed {} (content type {})",
+                    format,
+                    self.id,
+                    content_type.unwrap_or("unknown")
+                );
+                format
+            }
+        };
 
-            let feed = AtomFeed::read_from(std::io::Cursor::new(content))?;
+        // items without a parsable date are considered published when they were fetched
+        let fetched_at = Utc::now().fixed_offset();
 
-            Ok(feed
-                .entries
-                .iter()
-                .filter(|i| !i.links.is_empty())
-                .map(|i| (i.clone(), parse_variables_from_atom_item(i)))
-                .map(|i| DatabaseFeedItem {
-                    feed_name: self.id.clone(),
-                    external_id: get_unique_id_from_atom_item(&i.0, &self.regex),
-                    published_at: i.0.updated,
-                    variables: i.1,
-                })
-                .collect())
-        } else {
-            // parse as RSS
-            debug!("Parsing feed {} as RSS", self.id);
+        match format {
+            ConfigFeedFormat::Atom => self.parse_atom_feed(content),
+            ConfigFeedFormat::Json => self.parse_json_feed(content, fetched_at),
+            ConfigFeedFormat::Rss => self.parse_rss_feed(content, fetched_at),
+        }
+    }
 
-            let content = resp.bytes().await?;
-            let channel = Channel::read_from(&content[..])?;
+    fn parse_atom_feed(&self, content: &[u8]) -> Result<Vec<DatabaseFeedItem>> {
+        debug!("Parsing feed {} as Atom", self.id);
 
-            Ok(channel
-                .items
-                .iter()
-                .filter(|i| i.link.is_some())
-                .map(|i| (i.clone(), parse_variables_from_item(i)))
-                .map(|i| DatabaseFeedItem {
-                    feed_name: self.id.clone(),
-                    external_id: get_unique_id_from_item(&i.0, &self.regex),
-                    published_at: parse_datetime_from_item(&i.0),
-                    variables: i.1,
-                })
-                .collect())
+        let content = String::from_utf8_lossy(content);
+
+        // tenderned bodges
+
+        // remove atom namespaces
+        let content = content.replace("<atom:", "<");
+        let content = content.replace("</atom:", "</");
+
+        // remove xml declaration
+        let content = content.replace(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>",
+            "",
+        );
+
+        let feed = AtomFeed::read_from(std::io::Cursor::new(content))?;
+        let title = Some(feed.title.to_string());
+
+        Ok(feed
+            .entries
+            .iter()
+            .filter(|i| !i.links.is_empty())
+            .map(|i| (i.clone(), parse_variables_from_atom_item(i)))
+            .map(|i| {
+                self.new_item(
+                    get_unique_id_from_atom_item(&i.0, &self.regex),
+                    i.0.published.unwrap_or(i.0.updated),
+                    &title,
+                    i.1,
+                )
+            })
+            .collect())
+    }
+
+    fn parse_rss_feed(
+        &self,
+        content: &[u8],
+        fetched_at: DateTime<FixedOffset>,
+    ) -> Result<Vec<DatabaseFeedItem>> {
+        debug!("Parsing feed {} as RSS", self.id);
+
+        let channel = Channel::read_from(content)?;
+        let title = Some(channel.title.clone());
+
+        Ok(channel
+            .items
+            .iter()
+            .filter(|i| i.link.is_some())
+            .map(|i| (i.clone(), parse_variables_from_item(i)))
+            .map(|i| {
+                self.new_item(
+                    get_unique_id_from_item(&i.0, &self.regex),
+                    parse_datetime_from_item(&i.0, self.date_format.as_deref())
+                        .unwrap_or(fetched_at),
+                    &title,
+                    i.1,
+                )
+            })
+            .collect())
+    }
+
+    fn parse_json_feed(
+        &self,
+        content: &[u8],
+        fetched_at: DateTime<FixedOffset>,
+    ) -> Result<Vec<DatabaseFeedItem>> {
+        debug!("Parsing feed {} as JSON Feed", self.id);
+
+        let feed: JsonFeed = serde_json::from_slice(content)?;
+
+        if !feed.version.starts_with("https://jsonfeed.org/version/") {
+            return Err(anyhow!("unsupported JSON Feed version {}", feed.version));
+        }
+
+        Ok(feed
+            .items
+            .iter()
+            .filter(|i| i.url.is_some() || i.external_url.is_some())
+            .map(|i| (i.clone(), parse_variables_from_json_item(i)))
+            .map(|i| {
+                self.new_item(
+                    get_unique_id_from_json_item(&i.0, &self.regex),
+                    parse_datetime_from_json_item(&i.0, self.date_format.as_deref())
+                        .unwrap_or(fetched_at),
+                    &feed.title,
+                    i.1,
+                )
+            })
+            .collect())
+    }
+
+    fn new_item(
+        &self,
+        external_id: String,
+        published_at: DateTime<FixedOffset>,
+        feed_title: &Option<String>,
+        mut variables: BTreeMap<String, String>,
+    ) -> DatabaseFeedItem {
+        if let Some(title) = feed_title {
+            variables.insert(String::from("feed_title"), title.clone());
+        }
+
+        variables.insert(
+            String::from("pub_date"),
+            published_at
+                .with_timezone(&Tz::UTC)
+                .format("%v %R %Z")
+                .to_string(),
+        );
+
+        DatabaseFeedItem {
+            feed_name: self.id.clone(),
+            external_id,
+            published_at,
+            variables,
         }
     }
 }
 
+/// Key of the receiver in the delivery queue, derived from what it delivers to rather than its
+/// position, so reordering the receivers doesn't hand their messages to another receiver
+fn receiver_key(receiver: &ConfigFeedReceiver) -> String {
+    if let Some(id) = &receiver.id {
+        return id.clone();
+    }
+
+    let hash = Sha256::digest(receiver.receiver_type.target().as_bytes());
+
+    format!(
+        "{}-{}",
+        receiver.receiver_type.name(),
+        hex::encode(&hash[..8])
+    )
+}
+
+impl DigestSchedule {
+    fn from_config(digest: &ConfigFeedReceiverDigest) -> Result<Self> {
+        if let Some(schedule) = &digest.schedule {
+            return Schedule::from_str(schedule)
+                .map(|s| DigestSchedule::Cron(Box::new(s)))
+                .map_err(|e| anyhow!("invalid digest schedule {}: {}", schedule, e));
+        }
+
+        match digest.interval {
+            Some(interval) => Ok(DigestSchedule::Interval(TimeDelta::seconds(
+                interval as i64,
+            ))),
+            None => Err(anyhow!("digest needs a schedule or an interval")),
+        }
+    }
+
+    /// Time of the next digest when no items are waiting for one yet
+    fn next(&self) -> Result<DateTime<Utc>> {
+        match self {
+            DigestSchedule::Cron(schedule) => schedule
+                .upcoming(Utc)
+                .next()
+                .ok_or(PermanentError(String::from("digest schedule has no upcoming time")).into()),
+            DigestSchedule::Interval(interval) => Ok(Utc::now() + *interval),
+        }
+    }
+}
+
+/// Exponential backoff for the given attempt, capped at `BACKOFF_MAX_SECONDS`
+fn backoff(attempts: u32) -> TimeDelta {
+    let seconds = BACKOFF_BASE_SECONDS.saturating_mul(1 << attempts.saturating_sub(1).min(20));
+    TimeDelta::seconds(seconds.min(BACKOFF_MAX_SECONDS))
+}
+
+/// Detects the format from the root element or JSON body, falls back to the content type
+/// and finally to RSS
+fn detect_format(content: &[u8], content_type: Option<&str>) -> ConfigFeedFormat {
+    let content = String::from_utf8_lossy(&content[..content.len().min(4096)]);
+    let content = content.trim_start_matches('\u{feff}').trim_start();
+
+    if content.starts_with('{') {
+        return ConfigFeedFormat::Json;
+    }
+
+    if let Some(root) = root_element(content) {
+        match root.rsplit(':').next().unwrap_or(root) {
+            "rss" | "RDF" => return ConfigFeedFormat::Rss,
+            "feed" => return ConfigFeedFormat::Atom,
+            _ => {}
+        }
+    }
+
+    let content_type = content_type
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    match content_type.as_str() {
+        "application/atom+xml" => ConfigFeedFormat::Atom,
+        "application/feed+json" | "application/json" => ConfigFeedFormat::Json,
+        _ => ConfigFeedFormat::Rss,
+    }
+}
+
+/// Name of the first element, skipping the XML declaration, comments and doctype
+fn root_element(content: &str) -> Option<&str> {
+    let mut rest = content;
+
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = &comment[comment.find("-->")? + 3..];
+            continue;
+        }
+
+        if rest.starts_with('?') || rest.starts_with('!') {
+            continue;
+        }
+
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+
+        return Some(&rest[..end]);
+    }
+}
+
+fn header_to_string(resp: &Response, name: HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
 fn get_unique_id_from_item(item: &Item, regex: &Option<Regex>) -> String {
     let mut guid = item
         .guid
@@ -173,8 +1092,37 @@ fn get_unique_id_from_atom_item(item: &Entry, regex: &Option<Regex>) -> String {
     guid
 }
 
-fn parse_datetime_from_item(item: &Item) -> DateTime<FixedOffset> {
-    DateTime::parse_from_rfc2822(&item.pub_date.clone().unwrap_or_default()).unwrap_or_default()
+fn get_unique_id_from_json_item(item: &JsonFeedItem, regex: &Option<Regex>) -> String {
+    let mut guid = item.id();
+
+    if let Some(regex) = regex {
+        if let Ok(Some(m)) = regex.find(&guid) {
+            guid = m.as_str().to_owned();
+        }
+    }
+
+    guid
+}
+
+/// Tries `pubDate` and then `dc:date`
+fn parse_datetime_from_item(
+    item: &Item,
+    date_format: Option<&str>,
+) -> Option<DateTime<FixedOffset>> {
+    item.pub_date
+        .iter()
+        .chain(item.dublin_core_ext.iter().flat_map(|dc| dc.dates.iter()))
+        .find_map(|d| parse_datetime(d, date_format))
+}
+
+fn parse_datetime_from_json_item(
+    item: &JsonFeedItem,
+    date_format: Option<&str>,
+) -> Option<DateTime<FixedOffset>> {
+    item.date_published
+        .iter()
+        .chain(item.date_modified.iter())
+        .find_map(|d| parse_datetime(d, date_format))
 }
 
 fn parse_variables_from_item(item: &Item) -> BTreeMap<String, String> {
@@ -196,15 +1144,6 @@ fn parse_variables_from_item(item: &Item) -> BTreeMap<String, String> {
         variables.push((String::from("comments"), comments.clone()));
     }
 
-    if let Some(pub_date) = &item.pub_date {
-        let datetime = DateTime::parse_from_rfc2822(pub_date).unwrap_or_default();
-        let datetime = datetime.with_timezone(&Tz::UTC);
-        variables.push((
-            String::from("pub_date"),
-            datetime.format("%v %R %Z").to_string(),
-        ));
-    }
-
     variables.push((
         String::from("categories"),
         item.categories
@@ -214,42 +1153,67 @@ fn parse_variables_from_item(item: &Item) -> BTreeMap<String, String> {
             .join(", "),
     ));
 
-    let extentions: Vec<&Extension> = item
-        .extensions
+    if let Some(author) = &item.author {
+        variables.push((String::from("author"), author.clone()));
+    }
+
+    if let Some(enclosure) = &item.enclosure {
+        variables.push((String::from("enclosure_url"), enclosure.url.clone()));
+        variables.push((String::from("enclosure_type"), enclosure.mime_type.clone()));
+        variables.push((String::from("enclosure_length"), enclosure.length.clone()));
+    }
+
+    if let Some(source) = &item.source {
+        variables.push((String::from("source_url"), source.url.clone()));
+
+        if let Some(title) = &source.title {
+            variables.push((String::from("source_title"), title.clone()));
+        }
+    }
+
+    if let Some(dc) = &item.dublin_core_ext {
+        for (name, values) in [
+            ("dc_creator", &dc.creators),
+            ("dc_contributor", &dc.contributors),
+            ("dc_date", &dc.dates),
+            ("dc_subject", &dc.subjects),
+            ("dc_publisher", &dc.publishers),
+            ("dc_rights", &dc.rights),
+        ] {
+            if !values.is_empty() {
+                variables.push((String::from(name), values.join(", ")));
+            }
+        }
+
+        // feeds using dc:creator usually leave the RSS author empty
+        if item.author.is_none() && !dc.creators.is_empty() {
+            variables.push((String::from("author"), dc.creators.join(", ")));
+        }
+    }
+
+    if let Some(itunes) = &item.itunes_ext {
+        for (name, value) in [
+            ("itunes_author", &itunes.author),
+            ("itunes_duration", &itunes.duration),
+            ("itunes_image", &itunes.image),
+            ("itunes_episode", &itunes.episode),
+            ("itunes_season", &itunes.season),
+            ("itunes_episode_type", &itunes.episode_type),
+            ("itunes_explicit", &itunes.explicit),
+            ("itunes_subtitle", &itunes.subtitle),
+            ("itunes_summary", &itunes.summary),
+        ] {
+            if let Some(value) = value {
+                variables.push((String::from(name), value.clone()));
+            }
+        }
+    }
+
+    item.extensions
         .values()
         .flatten()
         .flat_map(|(_, m)| m)
-        .collect();
-
-    variables.append(
-        &mut extentions
-            .iter()
-            .flat_map(|ext| {
-                ext.attrs
-                    .iter()
-                    .map(|(key, value)| {
-                        (
-                            format!("{}_{}", ext.name.replace(':', "_"), key),
-                            value.clone(),
-                        )
-                    })
-                    .collect::<Vec<(String, String)>>()
-            })
-            .collect(),
-    );
-
-    variables.append(
-        &mut extentions
-            .iter()
-            .filter(|ext| ext.value.is_some())
-            .map(|ext| {
-                (
-                    ext.name.clone().replace(':', "_"),
-                    ext.value.clone().unwrap(),
-                )
-            })
-            .collect(),
-    );
+        .for_each(|ext| flatten_extension(ext, "", &mut variables));
 
     variables.into_iter().collect()
 }
@@ -271,11 +1235,6 @@ fn parse_variables_from_atom_item(item: &Entry) -> BTreeMap<String, String> {
         variables.push((String::from("author"), author.name.clone()));
     }
 
-    variables.push((
-        String::from("pub_date"),
-        item.updated.format("%v %R %Z").to_string(),
-    ));
-
     variables.push((
         String::from("categories"),
         item.categories
@@ -285,43 +1244,283 @@ fn parse_variables_from_atom_item(item: &Entry) -> BTreeMap<String, String> {
             .join(", "),
     ));
 
-    // TODO: handle extensions
-    // let extentions: Vec<&Extension> = item
-    //     .extensions
-    //     .values()
-    //     .flatten()
-    //     .flat_map(|(_, m)| m)
-    //     .collect();
-
-    // variables.append(
-    //     &mut extentions
-    //         .iter()
-    //         .flat_map(|ext| {
-    //             ext.attrs
-    //                 .iter()
-    //                 .map(|(key, value)| {
-    //                     (
-    //                         format!("{}_{}", ext.name.replace(':', "_"), key),
-    //                         value.clone(),
-    //                     )
-    //                 })
-    //                 .collect::<Vec<(String, String)>>()
-    //         })
-    //         .collect(),
-    // );
-
-    // variables.append(
-    //     &mut extentions
-    //         .iter()
-    //         .filter(|ext| ext.value.is_some())
-    //         .map(|ext| {
-    //             (
-    //                 ext.name.clone().replace(':', "_"),
-    //                 ext.value.clone().unwrap(),
-    //             )
-    //         })
-    //         .collect(),
-    // );
+    item.extensions
+        .values()
+        .flatten()
+        .flat_map(|(_, m)| m)
+        .for_each(|ext| flatten_extension(ext, "", &mut variables));
+
+    variables.into_iter().collect()
+}
+
+/// The RSS and Atom crates have their own, identical, extension types
+trait ExtensionNode: Sized {
+    fn name(&self) -> &str;
+    fn value(&self) -> Option<&str>;
+    fn attrs(&self) -> &BTreeMap<String, String>;
+    fn children(&self) -> &BTreeMap<String, Vec<Self>>;
+}
+
+impl ExtensionNode for Extension {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    fn attrs(&self) -> &BTreeMap<String, String> {
+        &self.attrs
+    }
+
+    fn children(&self) -> &BTreeMap<String, Vec<Self>> {
+        &self.children
+    }
+}
+
+impl ExtensionNode for AtomExtension {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    fn attrs(&self) -> &BTreeMap<String, String> {
+        &self.attrs
+    }
+
+    fn children(&self) -> &BTreeMap<String, Vec<Self>> {
+        &self.children
+    }
+}
+
+/// Adds the value and attributes of the extension and its children, nested names are
+/// joined with an underscore, e.g. `media_group_media_thumbnail_url`
+fn flatten_extension<E: ExtensionNode>(
+    ext: &E,
+    prefix: &str,
+    variables: &mut Vec<(String, String)>,
+) {
+    let name = format!("{}{}", prefix, ext.name().replace(':', "_"));
+
+    for (key, value) in ext.attrs() {
+        variables.push((format!("{}_{}", name, key), value.clone()));
+    }
+
+    if let Some(value) = ext.value().filter(|v| !v.trim().is_empty()) {
+        variables.push((name.clone(), value.to_owned()));
+    }
+
+    for child in ext.children().values().flatten() {
+        flatten_extension(child, &format!("{}_", name), variables);
+    }
+}
+
+fn parse_variables_from_json_item(item: &JsonFeedItem) -> BTreeMap<String, String> {
+    let mut variables: Vec<(String, String)> = Vec::new();
+
+    if let Some(title) = &item.title {
+        variables.push((String::from("title"), title.clone()));
+    }
+
+    if let Some(description) = item
+        .content_html
+        .as_ref()
+        .or(item.content_text.as_ref())
+        .or(item.summary.as_ref())
+    {
+        variables.push((String::from("description"), description.clone()));
+    }
+
+    if let Some(content_html) = &item.content_html {
+        variables.push((String::from("content_html"), content_html.clone()));
+    }
+
+    if let Some(content_text) = &item.content_text {
+        variables.push((String::from("content_text"), content_text.clone()));
+    }
+
+    if let Some(summary) = &item.summary {
+        variables.push((String::from("summary"), summary.clone()));
+    }
+
+    if let Some(link) = item.url.as_ref().or(item.external_url.as_ref()) {
+        variables.push((String::from("link"), link.clone()));
+    }
+
+    if let Some(external_url) = &item.external_url {
+        variables.push((String::from("external_url"), external_url.clone()));
+    }
+
+    if let Some(image) = &item.image {
+        variables.push((String::from("image"), image.clone()));
+    }
+
+    if let Some(banner_image) = &item.banner_image {
+        variables.push((String::from("banner_image"), banner_image.clone()));
+    }
+
+    let authors = item.authors();
+
+    if let Some(author) = authors.first() {
+        if let Some(name) = &author.name {
+            variables.push((String::from("author"), name.clone()));
+        }
+
+        if let Some(url) = &author.url {
+            variables.push((String::from("author_url"), url.clone()));
+        }
+
+        if let Some(avatar) = &author.avatar {
+            variables.push((String::from("author_avatar"), avatar.clone()));
+        }
+    }
+
+    variables.push((
+        String::from("authors"),
+        authors
+            .iter()
+            .filter_map(|a| a.name.clone())
+            .collect::<Vec<String>>()
+            .join(", "),
+    ));
+
+    variables.push((String::from("categories"), item.tags.join(", ")));
+    variables.push((String::from("tags"), item.tags.join(", ")));
 
     variables.into_iter().collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn detects_format_from_root_element() {
+        let rss =
+            b"<?xml version=\"1.0\"?>\n<!-- generated -->\n<rss version=\"2.0\"><channel/></rss>";
+        let rdf = b"<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\"></rdf:RDF>";
+        let atom = b"\xef\xbb\xbf  <feed xmlns=\"http://www.w3.org/2005/Atom\"></feed>";
+        let prefixed = b"<atom:feed xmlns:atom=\"http://www.w3.org/2005/Atom\"></atom:feed>";
+
+        assert_eq!(detect_format(rss, None), ConfigFeedFormat::Rss);
+        assert_eq!(detect_format(rdf, None), ConfigFeedFormat::Rss);
+        assert_eq!(detect_format(atom, None), ConfigFeedFormat::Atom);
+        assert_eq!(detect_format(prefixed, None), ConfigFeedFormat::Atom);
+    }
+
+    #[test]
+    fn root_element_wins_over_content_type() {
+        assert_eq!(
+            detect_format(b"<feed></feed>", Some("application/rss+xml")),
+            ConfigFeedFormat::Atom
+        );
+        assert_eq!(
+            detect_format(b"<rss></rss>", Some("application/atom+xml")),
+            ConfigFeedFormat::Rss
+        );
+    }
+
+    #[test]
+    fn detects_json() {
+        assert_eq!(
+            detect_format(
+                b"  {\"version\": \"https://jsonfeed.org/version/1.1\"}",
+                None
+            ),
+            ConfigFeedFormat::Json
+        );
+    }
+
+    #[test]
+    fn falls_back_to_content_type() {
+        assert_eq!(
+            detect_format(b"", Some("application/atom+xml; charset=utf-8")),
+            ConfigFeedFormat::Atom
+        );
+        assert_eq!(
+            detect_format(b"<html>", Some("Application/Feed+JSON")),
+            ConfigFeedFormat::Json
+        );
+        assert_eq!(
+            detect_format(b"<html>", Some("text/html")),
+            ConfigFeedFormat::Rss
+        );
+        assert_eq!(detect_format(b"", None), ConfigFeedFormat::Rss);
+    }
+
+    #[test]
+    fn only_reads_the_start_of_the_content() {
+        let mut content = vec![b' '; 5000];
+        content.extend_from_slice(b"<feed></feed>");
+
+        assert_eq!(detect_format(&content, None), ConfigFeedFormat::Rss);
+    }
+
+    fn feed_with_receiver(receiver: Value) -> Result<Feed> {
+        Feed::from_config(
+            serde_json::from_value(json!({
+                "id": "feed",
+                "rss_url": "https://example.org/feed.xml",
+                "interval": 60,
+                "receivers": [receiver]
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn rejects_digests_the_receiver_cannot_send() {
+        let webhook = json!({"url": "https://example.org/hook"});
+        let slack = json!({"webhook_url": "https://hooks.slack.com/services/x"});
+
+        let err = feed_with_receiver(json!({
+            "type": "webhook", "webhook": webhook, "digest": {"interval": 60}
+        }))
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .contains("webhook receivers don't support digests"));
+
+        assert!(feed_with_receiver(json!({
+            "type": "slack", "slack": slack, "digest": {"interval": 60}
+        }))
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_removals_the_receiver_cannot_do() {
+        let matrix = json!({
+            "homeserver_url": "https://matrix.example.org", "access_token": "x", "room": "!r:x"
+        });
+        let email = json!({
+            "host": "localhost", "from": "feeds@example.org", "to": ["a@example.org"]
+        });
+        let slack = json!({"webhook_url": "https://hooks.slack.com/services/x"});
+
+        for (receiver, on_removed, ok) in [
+            (json!({"type": "matrix", "matrix": matrix}), "delete", true),
+            (
+                json!({"type": "matrix", "matrix": matrix}),
+                "retract",
+                false,
+            ),
+            (json!({"type": "email", "email": email}), "retract", true),
+            (json!({"type": "email", "email": email}), "delete", false),
+            (json!({"type": "slack", "slack": slack}), "delete", false),
+            (json!({"type": "slack", "slack": slack}), "nothing", true),
+        ] {
+            let mut receiver = receiver;
+            receiver["on_removed"] = on_removed.into();
+
+            let result = feed_with_receiver(receiver.clone());
+            assert_eq!(result.is_ok(), ok, "{} {}", receiver, on_removed);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, Result};
+use fancy_regex::Regex;
+
+use crate::{config::ConfigFilter, database::DatabaseFeedItem, date::parse_datetime};
+
+/// Filter with its patterns compiled, built once when the config is loaded
+#[derive(Clone)]
+pub struct Filter {
+    field: Option<String>,
+    regex: Option<Regex>,
+    not_regex: Option<Regex>,
+    /// Lowercase keywords
+    contains_any: Vec<String>,
+    greater_than: Option<String>,
+    less_than: Option<String>,
+    all: Vec<Filter>,
+    any: Vec<Filter>,
+    not: Option<Box<Filter>>,
+}
+
+impl Filter {
+    pub fn from_config(config: &ConfigFilter) -> Result<Self> {
+        if config.has_conditions() && config.field.is_none() {
+            return Err(anyhow!("filter conditions without a field"));
+        }
+
+        let compile = |pattern: &Option<String>| -> Result<Option<Regex>> {
+            pattern
+                .as_ref()
+                .map(|p| Regex::new(p).map_err(|e| anyhow!("invalid filter regex {}: {}", p, e)))
+                .transpose()
+        };
+
+        Ok(Filter {
+            // the field is ignored without conditions
+            field: config.field.clone().filter(|_| config.has_conditions()),
+            regex: compile(&config.regex)?,
+            not_regex: compile(&config.not_regex)?,
+            contains_any: config
+                .contains_any
+                .iter()
+                .map(|k| k.to_lowercase())
+                .collect(),
+            greater_than: config.greater_than.clone(),
+            less_than: config.less_than.clone(),
+            all: config
+                .all
+                .iter()
+                .map(Filter::from_config)
+                .collect::<Result<_>>()?,
+            any: config
+                .any
+                .iter()
+                .map(Filter::from_config)
+                .collect::<Result<_>>()?,
+            not: config
+                .not
+                .as_ref()
+                .map(|n| Filter::from_config(n).map(Box::new))
+                .transpose()?,
+        })
+    }
+
+    pub fn matches(&self, item: &DatabaseFeedItem) -> Result<bool> {
+        for filter in &self.all {
+            if !filter.matches(item)? {
+                return Ok(false);
+            }
+        }
+
+        if !self.any.is_empty() {
+            let mut any = false;
+            for filter in &self.any {
+                if filter.matches(item)? {
+                    any = true;
+                    break;
+                }
+            }
+
+            if !any {
+                return Ok(false);
+            }
+        }
+
+        if let Some(not) = &self.not {
+            if not.matches(item)? {
+                return Ok(false);
+            }
+        }
+
+        let Some(field) = &self.field else {
+            return Ok(true);
+        };
+
+        // a missing variable never matches any of the conditions
+        let Some(value) = field_value(item, field) else {
+            return Ok(false);
+        };
+
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(&value)? {
+                return Ok(false);
+            }
+        }
+
+        if let Some(regex) = &self.not_regex {
+            if regex.is_match(&value)? {
+                return Ok(false);
+            }
+        }
+
+        if !self.contains_any.is_empty() {
+            let value = value.to_lowercase();
+            if !self.contains_any.iter().any(|k| value.contains(k)) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(other) = &self.greater_than {
+            if compare(&value, other) != Some(Ordering::Greater) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(other) = &self.less_than {
+            if compare(&value, other) != Some(Ordering::Less) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl ConfigFilter {
+    fn has_conditions(&self) -> bool {
+        self.regex.is_some()
+            || self.not_regex.is_some()
+            || !self.contains_any.is_empty()
+            || self.greater_than.is_some()
+            || self.less_than.is_some()
+    }
+}
+
+fn field_value(item: &DatabaseFeedItem, field: &str) -> Option<String> {
+    match field {
+        "published_at" => Some(item.published_at.to_rfc3339()),
+        _ => item.variable(field),
+    }
+}
+
+fn compare(value: &str, other: &str) -> Option<Ordering> {
+    if let (Ok(value), Ok(other)) = (value.trim().parse::<f64>(), other.trim().parse::<f64>()) {
+        return value.partial_cmp(&other);
+    }
+
+    Some(parse_datetime(value, None)?.cmp(&parse_datetime(other, None)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::database::test_item;
+
+    fn filter(config: Value) -> Filter {
+        Filter::from_config(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn item() -> DatabaseFeedItem {
+        let mut item = test_item("1", "Rust 1.80 Released");
+        item.variables
+            .insert(String::from("score"), String::from("42"));
+        item.variables
+            .insert(String::from("category"), String::from("Security"));
+        item
+    }
+
+    fn matches(config: Value) -> bool {
+        filter(config).matches(&item()).unwrap()
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(matches(json!({"field": "title", "regex": "^Rust \\d"})));
+        assert!(!matches(json!({"field": "title", "regex": "^Go"})));
+        assert!(matches(json!({"field": "title", "not_regex": "beta"})));
+        assert!(!matches(
+            json!({"field": "title", "not_regex": "(?i)released"})
+        ));
+    }
+
+    #[test]
+    fn folds_the_case_of_keywords() {
+        assert!(matches(
+            json!({"field": "category", "contains_any": ["SECURITY"]})
+        ));
+        assert!(matches(
+            json!({"field": "title", "contains_any": ["go", "rust"]})
+        ));
+        assert!(!matches(
+            json!({"field": "title", "contains_any": ["go", "java"]})
+        ));
+    }
+
+    #[test]
+    fn compares_numbers_and_dates() {
+        // numbers are not compared as text, where "42" would be less than "5"
+        assert!(matches(json!({"field": "score", "greater_than": "5"})));
+        assert!(matches(json!({"field": "score", "less_than": "100.5"})));
+        assert!(!matches(json!({"field": "score", "greater_than": "42"})));
+
+        assert!(matches(
+            json!({"field": "published_at", "greater_than": "2023-12-31T00:00:00Z"})
+        ));
+        assert!(matches(
+            json!({"field": "published_at", "less_than": "Tue, 02 Jan 2024 00:00:00 +0000"})
+        ));
+        assert!(!matches(
+            json!({"field": "published_at", "less_than": "2024-01-01T09:00:00Z"})
+        ));
+
+        // values that are neither never match
+        assert!(!matches(json!({"field": "title", "greater_than": "0"})));
+        assert!(!matches(
+            json!({"field": "title", "less_than": "2030-01-01T00:00:00Z"})
+        ));
+    }
+
+    #[test]
+    fn never_matches_missing_fields() {
+        assert!(!matches(json!({"field": "author", "not_regex": "anyone"})));
+        assert!(!matches(json!({"field": "author", "contains_any": [""]})));
+    }
+
+    #[test]
+    fn combines_filters() {
+        let rust = json!({"field": "title", "contains_any": ["rust"]});
+        let go = json!({"field": "title", "contains_any": ["go"]});
+
+        assert!(matches(
+            json!({"all": [rust, {"field": "score", "greater_than": "10"}]})
+        ));
+        assert!(!matches(json!({"all": [rust, go]})));
+        assert!(matches(json!({"any": [go, rust]})));
+        assert!(!matches(json!({"any": [go]})));
+        assert!(!matches(json!({"not": rust})));
+        assert!(matches(json!({"not": go})));
+        assert!(matches(json!({})));
+
+        // nested filters and the conditions of the filter itself all have to hold
+        assert!(!matches(
+            json!({"any": [rust], "field": "score", "less_than": "10"})
+        ));
+        assert!(matches(json!({"any": [rust], "not": {"all": [go]}})));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let config = |v: Value| serde_json::from_value::<ConfigFilter>(v).unwrap();
+
+        let err = Filter::from_config(&config(json!({"regex": "rust"})))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "filter conditions without a field");
+
+        assert!(Filter::from_config(&config(json!({"not": {"less_than": "1"}}))).is_err());
+        assert!(Filter::from_config(&config(json!({"field": "title", "regex": "("}))).is_err());
+
+        // a field without conditions is ignored
+        assert!(filter(json!({"field": "author"})).matches(&item()).unwrap());
+    }
+}
@@ -0,0 +1,56 @@
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+/// JSON Feed document, see https://www.jsonfeed.org/version/1.1/
+#[derive(Debug, Deserialize)]
+pub struct JsonFeed {
+    pub version: String,
+    pub title: Option<String>,
+    #[serde(default)]
+    pub items: Vec<JsonFeedItem>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JsonFeedItem {
+    /// Usually a string, but some publishers use numbers
+    pub id: Value,
+    pub url: Option<String>,
+    pub external_url: Option<String>,
+    pub title: Option<String>,
+    pub content_html: Option<String>,
+    pub content_text: Option<String>,
+    pub summary: Option<String>,
+    pub image: Option<String>,
+    pub banner_image: Option<String>,
+    pub date_published: Option<String>,
+    pub date_modified: Option<String>,
+    /// Only used by version 1.0, replaced by `authors` in 1.1
+    pub author: Option<JsonFeedAuthor>,
+    #[serde(default)]
+    pub authors: Vec<JsonFeedAuthor>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JsonFeedAuthor {
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub avatar: Option<String>,
+}
+
+impl JsonFeedItem {
+    pub fn id(&self) -> String {
+        match &self.id {
+            Value::String(id) => id.clone(),
+            id => id.to_string(),
+        }
+    }
+
+    pub fn authors(&self) -> Vec<JsonFeedAuthor> {
+        match (&self.author, self.authors.is_empty()) {
+            (Some(author), true) => vec![author.clone()],
+            _ => self.authors.clone(),
+        }
+    }
+}
@@ -1,6 +1,11 @@
 mod config;
 mod database;
+mod date;
 mod feed;
+mod filter;
+mod json_feed;
+mod markdown;
+mod quiet_hours;
 mod receivers;
 mod scheduler;
 
@@ -0,0 +1,480 @@
+use html_escape::decode_html_entities;
+
+/// Characters with a meaning in Discord markdown
+const MARKDOWN_CHARS: [char; 10] = ['\\', '*', '_', '~', '`', '|', '>', '#', '[', ']'];
+
+/// Elements whose content is never shown
+const HIDDEN_ELEMENTS: [&str; 4] = ["script", "style", "head", "template"];
+
+/// Escapes the characters Discord would interpret as markdown
+pub fn escape_markdown(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+
+    for c in input.chars() {
+        if MARKDOWN_CHARS.contains(&c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+
+    output
+}
+
+/// Escapes text for use in HTML, including attribute values
+pub fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Strips the tags and decodes the entities of HTML
+pub fn html_to_text(input: &str) -> String {
+    convert(input, false)
+}
+
+/// Converts HTML to Discord-flavored markdown, text is escaped so it is shown literally
+pub fn html_to_markdown(input: &str) -> String {
+    convert(input, true)
+}
+
+struct Converter {
+    markdown: bool,
+    output: String,
+    /// Inside `<pre>`, where whitespace is kept
+    preformatted: usize,
+    /// Inside `<code>`, where markdown is not interpreted
+    code: usize,
+    /// Inside an element that is not shown
+    hidden: usize,
+    /// Open `<a>` elements with their href and the output position of their text
+    links: Vec<(Option<String>, usize)>,
+    /// Open lists, with the next number for ordered lists
+    lists: Vec<Option<usize>>,
+}
+
+fn convert(input: &str, markdown: bool) -> String {
+    let mut converter = Converter {
+        markdown,
+        output: String::with_capacity(input.len()),
+        preformatted: 0,
+        code: 0,
+        hidden: 0,
+        links: Vec::new(),
+        lists: Vec::new(),
+    };
+
+    let mut rest = input;
+
+    while let Some(start) = rest.find('<') {
+        converter.text(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment
+                .find("-->")
+                .map(|end| &comment[end + 3..])
+                .unwrap_or("");
+            continue;
+        }
+
+        // a lone < is text, not a tag
+        if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!') {
+            converter.text("<");
+            rest = &rest[1..];
+            continue;
+        }
+
+        let Some(end) = tag_end(rest) else {
+            converter.text(rest);
+            rest = "";
+            break;
+        };
+
+        converter.tag(&rest[1..end]);
+        rest = &rest[end + 1..];
+    }
+
+    converter.text(rest);
+    converter.finish()
+}
+
+/// Position of the `>` closing the tag at the start of the input, skipping quoted attributes
+fn tag_end(input: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in input.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            (None, '<') => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut offset = 0;
+
+    while let Some(pos) = lower[offset..].find(name) {
+        let start = offset + pos;
+        offset = start + name.len();
+
+        let preceded = lower[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_whitespace());
+        let rest = lower[offset..].trim_start();
+
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+
+        let value = match value.chars().next() {
+            Some(q @ ('"' | '\'')) => value[1..].split(q).next().unwrap_or_default(),
+            _ => value
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default(),
+        };
+
+        return Some(decode_html_entities(value).into_owned());
+    }
+
+    None
+}
+
+impl Converter {
+    fn text(&mut self, text: &str) {
+        if self.hidden > 0 || text.is_empty() {
+            return;
+        }
+
+        let text = decode_html_entities(text);
+
+        let text = if self.preformatted > 0 {
+            text.into_owned()
+        } else {
+            let mut collapsed = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+            if text.starts_with(char::is_whitespace) && !collapsed.is_empty() {
+                collapsed.insert(0, ' ');
+            }
+
+            if text.ends_with(char::is_whitespace) {
+                collapsed.push(' ');
+            }
+
+            // leading whitespace is not shown at the start of a line
+            if self.output.is_empty() || self.output.ends_with(['\n', ' ']) {
+                collapsed.trim_start().to_owned()
+            } else {
+                collapsed
+            }
+        };
+
+        if self.markdown && self.preformatted == 0 && self.code == 0 {
+            self.output.push_str(&escape_markdown(&text));
+        } else {
+            self.output.push_str(&text);
+        }
+    }
+
+    fn tag(&mut self, tag: &str) {
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/').trim_end_matches('/');
+        let name = tag
+            .split(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if HIDDEN_ELEMENTS.contains(&name.as_str()) {
+            if closing {
+                self.hidden = self.hidden.saturating_sub(1);
+            } else {
+                self.hidden += 1;
+            }
+            return;
+        }
+
+        if self.hidden > 0 {
+            return;
+        }
+
+        match (name.as_str(), closing) {
+            ("br", _) => self.output.push('\n'),
+            ("p" | "div" | "section" | "article" | "table" | "figure", _) => self.block(),
+            ("tr", true) => self.line(),
+            ("td" | "th", true) => self.output.push(' '),
+            ("hr", _) => {
+                self.block();
+                self.output.push_str("---");
+                self.block();
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", _) => {
+                if closing {
+                    self.markup("**");
+                    self.block();
+                } else {
+                    self.block();
+                    self.markup("**");
+                }
+            }
+            ("b" | "strong", _) => self.markup("**"),
+            ("i" | "em", _) => self.markup("*"),
+            ("u" | "ins", _) => self.markup("__"),
+            ("s" | "strike" | "del", _) => self.markup("~~"),
+            ("code", false) if self.preformatted == 0 => {
+                self.markup("`");
+                self.code += 1;
+            }
+            ("code", true) if self.preformatted == 0 => {
+                self.code = self.code.saturating_sub(1);
+                self.markup("`");
+            }
+            ("pre", false) => {
+                self.block();
+                self.markup("```\n");
+                self.preformatted += 1;
+            }
+            ("pre", true) => {
+                self.preformatted = self.preformatted.saturating_sub(1);
+                self.line();
+                self.markup("```");
+                self.block();
+            }
+            ("blockquote", false) => {
+                self.block();
+                self.markup("> ");
+            }
+            ("blockquote", true) => self.block(),
+            ("ul", false) => {
+                self.line();
+                self.lists.push(None);
+            }
+            ("ol", false) => {
+                self.line();
+                self.lists.push(Some(1));
+            }
+            ("ul" | "ol", true) => {
+                self.lists.pop();
+                self.line();
+            }
+            ("li", false) => {
+                self.line();
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let bullet = match self.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}{}. ", indent, *n - 1)
+                    }
+                    _ => format!("{}- ", indent),
+                };
+                self.output.push_str(&bullet);
+            }
+            ("a", false) => {
+                let href = attribute(tag, "href");
+                if self.markdown && href.is_some() {
+                    self.output.push('[');
+                }
+                self.links.push((href, self.output.len()));
+            }
+            ("a", true) => self.close_link(),
+            _ => {}
+        }
+    }
+
+    fn close_link(&mut self) {
+        let Some((href, start)) = self.links.pop() else {
+            return;
+        };
+
+        let Some(href) = href else {
+            return;
+        };
+
+        if !self.markdown {
+            return;
+        }
+
+        let text = self.output[start..].trim().to_owned();
+
+        // a link without text, or with the url as text, is shown as the url itself
+        if text.is_empty() || text == escape_markdown(&href) {
+            self.output.truncate(start - 1);
+            self.output.push_str(&href);
+        } else {
+            self.output.push_str(&format!("]({})", href));
+        }
+    }
+
+    fn markup(&mut self, markup: &str) {
+        if self.markdown {
+            self.output.push_str(markup);
+        }
+    }
+
+    /// Starts a new line unless the output is already at the start of one
+    fn line(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with('\n') {
+            self.output.push('\n');
+        }
+    }
+
+    /// Starts a new paragraph
+    fn block(&mut self) {
+        self.line();
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push('\n');
+        }
+    }
+
+    fn finish(mut self) -> String {
+        while !self.links.is_empty() {
+            self.close_link();
+        }
+
+        let mut output = String::with_capacity(self.output.len());
+        let mut newlines = 0;
+
+        for line in self.output.lines() {
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                newlines += 1;
+                continue;
+            }
+
+            if !output.is_empty() {
+                output.push_str(if newlines > 0 { "\n\n" } else { "\n" });
+            }
+
+            output.push_str(line);
+            newlines = 0;
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(
+            escape_markdown("*a* _b_ ~c~ `d` |e| > #f [g](h) \\"),
+            "\\*a\\* \\_b\\_ \\~c\\~ \\`d\\` \\|e\\| \\> \\#f \\[g\\](h) \\\\"
+        );
+        assert_eq!(escape_markdown("plain ü text"), "plain ü text");
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">Fish & 'chips'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Fish &amp; &#39;chips&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("&amp;"), "&amp;amp;");
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(
+            html_to_text("Fish &amp; chips &lt;3 &quot;ok&quot; &#39;x&#39; caf&eacute;"),
+            "Fish & chips <3 \"ok\" 'x' café"
+        );
+        assert_eq!(
+            html_to_markdown("&lt;b&gt; *not bold* &amp;"),
+            "<b\\> \\*not bold\\* &"
+        );
+    }
+
+    #[test]
+    fn converts_nested_lists() {
+        let html = "<ul><li>One<ul><li>Two</li><li>Three</li></ul></li><li>Four</li></ul>\
+            <ol><li>First</li><li>Second</li></ol>";
+
+        assert_eq!(
+            html_to_markdown(html),
+            "- One\n  - Two\n  - Three\n- Four\n1. First\n2. Second"
+        );
+    }
+
+    #[test]
+    fn converts_links() {
+        assert_eq!(
+            html_to_markdown("<a href=\"https://example.com/?a=1&amp;b=2\">Some *link*</a>"),
+            "[Some \\*link\\*](https://example.com/?a=1&b=2)"
+        );
+        assert_eq!(
+            html_to_markdown("<a href='https://example.com'>https://example.com</a>"),
+            "https://example.com"
+        );
+        assert_eq!(
+            html_to_markdown("<a href=https://example.com></a>"),
+            "https://example.com"
+        );
+        assert_eq!(html_to_markdown("<a name=\"top\">Top</a>"), "Top");
+        assert_eq!(
+            html_to_text("<a href=\"https://example.com\">Example</a>"),
+            "Example"
+        );
+    }
+
+    #[test]
+    fn converts_code() {
+        assert_eq!(
+            html_to_markdown("<p>Call <code>do_it(*args)</code> once</p>"),
+            "Call `do_it(*args)` once"
+        );
+        assert_eq!(
+            html_to_markdown("<pre><code>fn main() {\n    let x = *y;\n}</code></pre><p>After</p>"),
+            "```\nfn main() {\n    let x = *y;\n}\n```\n\nAfter"
+        );
+    }
+
+    #[test]
+    fn skips_hidden_elements_and_comments() {
+        assert_eq!(
+            html_to_text(
+                "<style>p { color: red }</style>Visible<!-- hidden --> <script>x()</script>text"
+            ),
+            "Visible text"
+        );
+    }
+
+    #[test]
+    fn reads_attributes_after_non_ascii() {
+        // lowercasing changes the length of the dotted I and the Kelvin sign
+        assert_eq!(
+            html_to_markdown(
+                "<a HREF=\"https://example.com/\u{fc}\" title=\"\u{130}stanbul\">Link</a>"
+            ),
+            "[Link](https://example.com/\u{fc})"
+        );
+        assert_eq!(
+            attribute(
+                "a href=\"https://example.com\" title=\"\u{212a}\u{212a}\"",
+                "href"
+            ),
+            Some(String::from("https://example.com"))
+        );
+        assert_eq!(
+            attribute("a data-name='\u{130}\u{130}' href=x", "href"),
+            Some(String::from("x"))
+        );
+        assert_eq!(attribute("a title='\u{130}href=x'", "href"), None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::{config::ConfigFeedReceiverQuietHours, filter::Filter};
+
+/// Quiet hours of a receiver, parsed when the config is loaded
+#[derive(Clone)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Tz,
+    /// Items matching this filter are delivered during the quiet hours as well
+    pub urgent: Option<Filter>,
+}
+
+impl QuietHours {
+    pub fn from_config(config: &ConfigFeedReceiverQuietHours) -> Result<Self> {
+        Ok(QuietHours {
+            start: parse_time(&config.start)?,
+            end: parse_time(&config.end)?,
+            timezone: match &config.timezone {
+                Some(timezone) => Tz::from_str(timezone)
+                    .map_err(|e| anyhow!("invalid quiet hours timezone {}: {}", timezone, e))?,
+                None => Tz::UTC,
+            },
+            urgent: config
+                .urgent
+                .as_ref()
+                .map(Filter::from_config)
+                .transpose()?,
+        })
+    }
+
+    /// End of the quiet hours when `now` falls within them
+    pub fn until(&self, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+        let local = now.with_timezone(&self.timezone);
+        let time = local.time();
+
+        // quiet hours usually span midnight, e.g. 22:00 until 07:00
+        let quiet = if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        };
+
+        if !quiet {
+            return Ok(None);
+        }
+
+        let mut date = local.date_naive();
+        if time >= self.end {
+            date = date.succ_opt().ok_or(anyhow!("date out of range"))?;
+        }
+
+        // the end may not exist on the day daylight saving time starts
+        let end = date.and_time(self.end);
+        let until = self
+            .timezone
+            .from_local_datetime(&end)
+            .earliest()
+            .or_else(|| {
+                self.timezone
+                    .from_local_datetime(&(end + TimeDelta::hours(1)))
+                    .earliest()
+            })
+            .ok_or(anyhow!("invalid end of quiet hours"))?;
+
+        Ok(Some(until.with_timezone(&Utc)))
+    }
+}
+
+fn parse_time(input: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(input, "%H:%M")
+        .map_err(|e| anyhow!("invalid quiet hours time {}: {}", input, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet_hours(start: &str, end: &str, timezone: Option<&str>) -> Result<QuietHours> {
+        QuietHours::from_config(&ConfigFeedReceiverQuietHours {
+            start: String::from(start),
+            end: String::from(end),
+            timezone: timezone.map(String::from),
+            urgent: None,
+        })
+    }
+
+    fn until(quiet_hours: &QuietHours, now: &str) -> Option<String> {
+        let now = DateTime::parse_from_rfc3339(now)
+            .unwrap()
+            .with_timezone(&Utc);
+        quiet_hours.until(now).unwrap().map(|u| u.to_rfc3339())
+    }
+
+    #[test]
+    fn holds_within_a_day() {
+        let quiet = quiet_hours("09:00", "17:00", None).unwrap();
+
+        assert_eq!(until(&quiet, "2024-01-10T08:59:59Z"), None);
+        assert_eq!(
+            until(&quiet, "2024-01-10T09:00:00Z").as_deref(),
+            Some("2024-01-10T17:00:00+00:00")
+        );
+        assert_eq!(until(&quiet, "2024-01-10T17:00:00Z"), None);
+    }
+
+    #[test]
+    fn holds_across_midnight() {
+        let quiet = quiet_hours("22:00", "07:00", Some("Europe/Amsterdam")).unwrap();
+
+        // Amsterdam is an hour ahead of UTC in winter
+        assert_eq!(until(&quiet, "2024-01-10T20:59:00Z"), None);
+        assert_eq!(
+            until(&quiet, "2024-01-10T21:00:00Z").as_deref(),
+            Some("2024-01-11T06:00:00+00:00")
+        );
+        assert_eq!(
+            until(&quiet, "2024-01-10T23:30:00Z").as_deref(),
+            Some("2024-01-11T06:00:00+00:00")
+        );
+        assert_eq!(
+            until(&quiet, "2024-01-11T02:00:00Z").as_deref(),
+            Some("2024-01-11T06:00:00+00:00")
+        );
+        assert_eq!(until(&quiet, "2024-01-11T06:00:00Z"), None);
+    }
+
+    #[test]
+    fn ends_after_the_gap_when_daylight_saving_time_starts() {
+        // 02:30 does not exist on March 31 2024 in Amsterdam, the clocks go from 02:00 to 03:00
+        let quiet = quiet_hours("22:00", "02:30", Some("Europe/Amsterdam")).unwrap();
+
+        assert_eq!(
+            until(&quiet, "2024-03-30T23:00:00Z").as_deref(),
+            Some("2024-03-31T01:30:00+00:00")
+        );
+    }
+
+    #[test]
+    fn ends_at_the_first_occurrence_when_daylight_saving_time_ends() {
+        // 02:30 occurs twice on October 27 2024 in Amsterdam, first at +02:00
+        let quiet = quiet_hours("22:00", "02:30", Some("Europe/Amsterdam")).unwrap();
+
+        assert_eq!(
+            until(&quiet, "2024-10-26T21:00:00Z").as_deref(),
+            Some("2024-10-27T00:30:00+00:00")
+        );
+    }
+
+    #[test]
+    fn follows_the_offset_of_the_day() {
+        let quiet = quiet_hours("22:00", "07:00", Some("Europe/Amsterdam")).unwrap();
+
+        // Amsterdam is two hours ahead of UTC in summer
+        assert_eq!(until(&quiet, "2024-07-10T19:30:00Z"), None);
+        assert_eq!(
+            until(&quiet, "2024-07-10T20:00:00Z").as_deref(),
+            Some("2024-07-11T05:00:00+00:00")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(quiet_hours("24:00", "07:00", None).is_err());
+        assert!(quiet_hours("22:00", "7am", None).is_err());
+        assert!(quiet_hours("22:00", "07:00", Some("Mars/Olympus_Mons")).is_err());
+    }
+}
@@ -1,8 +1,441 @@
-use anyhow::Result;
+use std::{fmt, future::Future, time::Duration};
 
-use crate::database::DatabaseFeedItem;
+use anyhow::{anyhow, Result};
+use log::warn;
+use sha2::{Digest, Sha256};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    config::{
+        ConfigFeedReceiver, ConfigFeedReceiverDigest, ConfigFeedReceiverRemoval,
+        ConfigFeedReceiverType,
+    },
+    database::DatabaseFeedItem,
+};
+
+use self::{
+    discord::DiscordReceiver, email::EmailReceiver, matrix::MatrixReceiver, slack::SlackReceiver,
+    telegram::TelegramReceiver, webhook::WebhookReceiver,
+};
 
 pub mod discord;
+pub mod email;
+pub mod matrix;
+pub mod slack;
+pub mod telegram;
+pub mod webhook;
+
+const MAX_RATE_LIMIT_RETRIES: usize = 5;
+const DEFAULT_DIGEST_TITLE: &str = "$count new items";
+
 pub trait Receivable {
-    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<()>;
+    /// Sends the item, returns a reference to the sent message if the receiver can edit it
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>>;
+
+    /// Maximum amount of new items sent in a single message, `None` when the receiver sends
+    /// every item on its own
+    fn batch_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Sends as many of the new items as fit in a single message, returns how many were sent.
+    /// Batched messages can't be edited afterwards
+    async fn send_batch(&self, items: &[DatabaseFeedItem]) -> Result<usize> {
+        match items.first() {
+            Some(item) => self.send_item(item).await.map(|_| 1),
+            None => Ok(0),
+        }
+    }
+
+    /// Whether the receiver implements `send_digest`
+    fn supports_digests(&self) -> bool {
+        false
+    }
+
+    /// Whether the receiver can delete or retract its messages as configured by `removal`
+    fn supports_removal(&self, removal: ConfigFeedReceiverRemoval) -> bool {
+        removal == ConfigFeedReceiverRemoval::Nothing
+    }
+
+    /// Sends a single summary of the items. Receivers that split it across several messages
+    /// count the items of the messages already posted in `sent`, so those are not sent again
+    /// when a later message fails
+    async fn send_digest(
+        &self,
+        _digest: &ConfigFeedReceiverDigest,
+        _items: &[DatabaseFeedItem],
+        _sent: &mut usize,
+    ) -> Result<()> {
+        Err(PermanentError(String::from("receiver does not support digests")).into())
+    }
+
+    /// Replaces the message previously sent for the item with its current content
+    async fn update_item(&self, _item: &DatabaseFeedItem, _message_ref: &str) -> Result<()> {
+        Err(PermanentError(String::from("receiver does not support editing messages")).into())
+    }
+
+    /// Edits the message previously sent for the item to mark it as retracted
+    async fn retract_item(&self, _item: &DatabaseFeedItem, _message_ref: &str) -> Result<()> {
+        Err(PermanentError(String::from("receiver does not support editing messages")).into())
+    }
+
+    /// Deletes the message previously sent for an item
+    async fn delete_item(&self, _message_ref: &str) -> Result<()> {
+        Err(PermanentError(String::from("receiver does not support deleting messages")).into())
+    }
+}
+
+/// Error for deliveries that can never succeed, these are dead-lettered without retrying
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+/// Receiver for a configured receiver type
+#[derive(Clone)]
+pub enum Receiver {
+    Discord(Box<DiscordReceiver>),
+    Slack(SlackReceiver),
+    Matrix(MatrixReceiver),
+    Telegram(TelegramReceiver),
+    Webhook(WebhookReceiver),
+    Email(EmailReceiver),
+}
+
+impl Receiver {
+    pub fn from_config(config: &ConfigFeedReceiver) -> Result<Self> {
+        Ok(match &config.receiver_type {
+            ConfigFeedReceiverType::Discord { discord } => {
+                Receiver::Discord(Box::new(DiscordReceiver::new(discord)))
+            }
+            ConfigFeedReceiverType::Slack { slack } => Receiver::Slack(SlackReceiver::new(slack)),
+            ConfigFeedReceiverType::Matrix { matrix } => {
+                Receiver::Matrix(MatrixReceiver::new(matrix))
+            }
+            ConfigFeedReceiverType::Telegram { telegram } => {
+                Receiver::Telegram(TelegramReceiver::new(telegram))
+            }
+            ConfigFeedReceiverType::Webhook { webhook } => {
+                Receiver::Webhook(WebhookReceiver::new(webhook)?)
+            }
+            ConfigFeedReceiverType::Email { email } => Receiver::Email(EmailReceiver::new(email)),
+        })
+    }
+}
+
+impl Receivable for Receiver {
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        match self {
+            Receiver::Discord(r) => r.send_item(item).await,
+            Receiver::Slack(r) => r.send_item(item).await,
+            Receiver::Matrix(r) => r.send_item(item).await,
+            Receiver::Telegram(r) => r.send_item(item).await,
+            Receiver::Webhook(r) => r.send_item(item).await,
+            Receiver::Email(r) => r.send_item(item).await,
+        }
+    }
+
+    fn batch_size(&self) -> Option<usize> {
+        match self {
+            Receiver::Discord(r) => r.batch_size(),
+            Receiver::Slack(r) => r.batch_size(),
+            Receiver::Matrix(r) => r.batch_size(),
+            Receiver::Telegram(r) => r.batch_size(),
+            Receiver::Webhook(r) => r.batch_size(),
+            Receiver::Email(r) => r.batch_size(),
+        }
+    }
+
+    fn supports_digests(&self) -> bool {
+        match self {
+            Receiver::Discord(r) => r.supports_digests(),
+            Receiver::Slack(r) => r.supports_digests(),
+            Receiver::Matrix(r) => r.supports_digests(),
+            Receiver::Telegram(r) => r.supports_digests(),
+            Receiver::Webhook(r) => r.supports_digests(),
+            Receiver::Email(r) => r.supports_digests(),
+        }
+    }
+
+    fn supports_removal(&self, removal: ConfigFeedReceiverRemoval) -> bool {
+        match self {
+            Receiver::Discord(r) => r.supports_removal(removal),
+            Receiver::Slack(r) => r.supports_removal(removal),
+            Receiver::Matrix(r) => r.supports_removal(removal),
+            Receiver::Telegram(r) => r.supports_removal(removal),
+            Receiver::Webhook(r) => r.supports_removal(removal),
+            Receiver::Email(r) => r.supports_removal(removal),
+        }
+    }
+
+    async fn send_batch(&self, items: &[DatabaseFeedItem]) -> Result<usize> {
+        match self {
+            Receiver::Discord(r) => r.send_batch(items).await,
+            Receiver::Slack(r) => r.send_batch(items).await,
+            Receiver::Matrix(r) => r.send_batch(items).await,
+            Receiver::Telegram(r) => r.send_batch(items).await,
+            Receiver::Webhook(r) => r.send_batch(items).await,
+            Receiver::Email(r) => r.send_batch(items).await,
+        }
+    }
+
+    async fn send_digest(
+        &self,
+        digest: &ConfigFeedReceiverDigest,
+        items: &[DatabaseFeedItem],
+        sent: &mut usize,
+    ) -> Result<()> {
+        match self {
+            Receiver::Discord(r) => r.send_digest(digest, items, sent).await,
+            Receiver::Slack(r) => r.send_digest(digest, items, sent).await,
+            Receiver::Matrix(r) => r.send_digest(digest, items, sent).await,
+            Receiver::Telegram(r) => r.send_digest(digest, items, sent).await,
+            Receiver::Webhook(r) => r.send_digest(digest, items, sent).await,
+            Receiver::Email(r) => r.send_digest(digest, items, sent).await,
+        }
+    }
+
+    async fn update_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        match self {
+            Receiver::Discord(r) => r.update_item(item, message_ref).await,
+            Receiver::Slack(r) => r.update_item(item, message_ref).await,
+            Receiver::Matrix(r) => r.update_item(item, message_ref).await,
+            Receiver::Telegram(r) => r.update_item(item, message_ref).await,
+            Receiver::Webhook(r) => r.update_item(item, message_ref).await,
+            Receiver::Email(r) => r.update_item(item, message_ref).await,
+        }
+    }
+
+    async fn retract_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        match self {
+            Receiver::Discord(r) => r.retract_item(item, message_ref).await,
+            Receiver::Slack(r) => r.retract_item(item, message_ref).await,
+            Receiver::Matrix(r) => r.retract_item(item, message_ref).await,
+            Receiver::Telegram(r) => r.retract_item(item, message_ref).await,
+            Receiver::Webhook(r) => r.retract_item(item, message_ref).await,
+            Receiver::Email(r) => r.retract_item(item, message_ref).await,
+        }
+    }
+
+    async fn delete_item(&self, message_ref: &str) -> Result<()> {
+        match self {
+            Receiver::Discord(r) => r.delete_item(message_ref).await,
+            Receiver::Slack(r) => r.delete_item(message_ref).await,
+            Receiver::Matrix(r) => r.delete_item(message_ref).await,
+            Receiver::Telegram(r) => r.delete_item(message_ref).await,
+            Receiver::Webhook(r) => r.delete_item(message_ref).await,
+            Receiver::Email(r) => r.delete_item(message_ref).await,
+        }
+    }
+}
+
+/// Truncates to the amount of characters, cutting at a grapheme boundary and ending with an
+/// ellipsis when the input was too long
+pub fn trunc(input: &str, len: usize) -> String {
+    if input.chars().count() <= len {
+        return input.to_owned();
+    }
+
+    let mut output = String::new();
+    let mut count = 0;
+
+    for grapheme in input.graphemes(true) {
+        let chars = grapheme.chars().count();
+
+        // leave room for the ellipsis
+        if count + chars + 1 > len {
+            break;
+        }
+
+        output.push_str(grapheme);
+        count += chars;
+    }
+
+    output.push('…');
+    output
+}
+
+/// Result of a single request to an API that may ask to slow down
+pub enum Attempt<T> {
+    Done(T),
+    RateLimited(Duration),
+}
+
+/// Repeats the request as long as `service` rate limits it, waiting as long as it asks
+pub async fn retry_rate_limited<T, F, Fut>(service: &str, mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Attempt<T>>>,
+{
+    for _ in 0..MAX_RATE_LIMIT_RETRIES {
+        match request().await? {
+            Attempt::Done(value) => return Ok(value),
+            Attempt::RateLimited(retry_after) => {
+                warn!("Rate limited by {}, retrying in {:?}", service, retry_after);
+                tokio::time::sleep(retry_after).await;
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "still rate limited by {} after {} attempts",
+        service,
+        MAX_RATE_LIMIT_RETRIES
+    ))
+}
+
+/// Heading of the digest, rendered with the variables of the first item and `$count`
+pub fn digest_title(
+    digest: &ConfigFeedReceiverDigest,
+    items: &[DatabaseFeedItem],
+    escape: fn(&str) -> String,
+) -> String {
+    let Some(first) = items.first() else {
+        return String::new();
+    };
+
+    let mut summary = first.clone();
+    summary
+        .variables
+        .insert(String::from("count"), items.len().to_string());
+
+    summary.sub_escaped(
+        digest.title.as_deref().unwrap_or(DEFAULT_DIGEST_TITLE),
+        escape,
+    )
+}
+
+/// Joins the lines of a digest into chunks of less than `limit` characters, the first one
+/// starting with `head`. Lines are never split, each chunk keeps the amount of items up to its
+/// last line so the items of the chunks already sent are known when a later one fails
+pub fn chunk_lines(
+    head: String,
+    lines: impl IntoIterator<Item = String>,
+    limit: usize,
+) -> Vec<(String, usize)> {
+    let mut chunks = vec![(head, 0)];
+
+    for (i, line) in lines.into_iter().enumerate() {
+        let (chunk, count) = chunks.last_mut().unwrap();
+
+        if chunk.is_empty() {
+            chunk.push_str(&line);
+            *count = i + 1;
+        } else if chunk.chars().count() + line.chars().count() < limit {
+            chunk.push('\n');
+            chunk.push_str(&line);
+            *count = i + 1;
+        } else {
+            chunks.push((line, i + 1));
+        }
+    }
+
+    chunks
+}
+
+/// Hex digest of the parts that stays the same across restarts and builds, each part is
+/// prefixed with its length so their boundaries are part of the digest
+pub fn stable_id(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+
+    hex::encode(&hasher.finalize()[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_item;
+
+    #[test]
+    fn trunc_keeps_input_that_fits() {
+        assert_eq!(trunc("", 0), "");
+        assert_eq!(trunc("abcde", 5), "abcde");
+        assert_eq!(trunc("äöü", 3), "äöü");
+    }
+
+    #[test]
+    fn trunc_ends_with_ellipsis() {
+        assert_eq!(trunc("abcdef", 5), "abcd…");
+        assert_eq!(trunc("abcdef", 1), "…");
+        assert_eq!(trunc("äöüäöü", 4).chars().count(), 4);
+    }
+
+    #[test]
+    fn trunc_keeps_graphemes_whole() {
+        // e followed by a combining accent is two characters but one grapheme
+        assert_eq!(trunc("ae\u{301}bc", 3), "a…");
+        assert_eq!(trunc("ae\u{301}bc", 4), "ae\u{301}…");
+
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        assert_eq!(trunc(&format!("{}abc", family), 5), "…");
+        assert_eq!(trunc(&format!("{}abc", family), 6), format!("{}…", family));
+    }
+
+    #[test]
+    fn chunks_lines_below_the_limit() {
+        let lines = ["aaaa", "bbbb", "cccc", "dddddddddddd", "e"].map(String::from);
+
+        assert_eq!(
+            chunk_lines(String::from("T"), lines, 10),
+            vec![
+                (String::from("T\naaaa"), 1),
+                (String::from("bbbb\ncccc"), 3),
+                (String::from("dddddddddddd"), 4),
+                (String::from("e"), 5),
+            ]
+        );
+        assert_eq!(
+            chunk_lines(String::from("Title"), [String::from("aaaaa")], 10),
+            vec![(String::from("Title"), 0), (String::from("aaaaa"), 1)]
+        );
+        assert_eq!(
+            chunk_lines(String::new(), Vec::new(), 10),
+            vec![(String::new(), 0)]
+        );
+    }
+
+    #[test]
+    fn renders_digest_titles_with_the_count() {
+        let digest = ConfigFeedReceiverDigest {
+            schedule: None,
+            interval: Some(60),
+            title: Some(String::from("$count from <$title>")),
+            line: None,
+        };
+        let items = [test_item("1", "A & B"), test_item("2", "C")];
+
+        assert_eq!(
+            digest_title(&digest, &items, str::to_owned),
+            "2 from <A & B>"
+        );
+        assert_eq!(
+            digest_title(&digest, &items, crate::markdown::escape_html),
+            "2 from <A &amp; B>"
+        );
+    }
+
+    #[test]
+    fn stable_id_is_fixed() {
+        assert_eq!(
+            stable_id(&["feed", "guid"]),
+            "f9e15f97691a9f5425afb59215d0adb6"
+        );
+    }
+
+    #[test]
+    fn stable_id_keeps_part_boundaries() {
+        assert_ne!(stable_id(&["ab", "c"]), stable_id(&["a", "bc"]));
+        assert_ne!(stable_id(&["a", ""]), stable_id(&["a"]));
+    }
 }
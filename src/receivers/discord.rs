@@ -1,9 +1,47 @@
-use crate::config::ConfigFeedDiscordReceiver;
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{ConfigFeedDiscordReceiver, ConfigFeedReceiverDigest, ConfigFeedReceiverRemoval},
+    database::DatabaseFeedItem,
+};
+use anyhow::{anyhow, Result};
 use fancy_regex::Regex;
+use reqwest::{header::HeaderMap, Method, StatusCode, Url};
+use serde_derive::Deserialize;
 use serde_json::{json, Value};
 
-use super::Receivable;
+use super::{
+    chunk_lines, digest_title, retry_rate_limited, trunc, Attempt, PermanentError, Receivable,
+};
+
+const GLOBAL_BUCKET: &str = "global";
+const DEFAULT_RETRACTED_MARKER: &str = "**Retracted**";
+const DEFAULT_DIGEST_LINE: &str = "- [$title_escaped]($link)";
+
+const MAX_CONTENT_LENGTH: usize = 2000;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_EMBEDS: usize = 10;
+const MAX_FIELDS: usize = 25;
+const MAX_TOTAL_EMBED_LENGTH: usize = 6000;
 
+/// Instant until which a webhook (or every webhook for the global bucket) is rate limited
+static RATE_LIMITS: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize, Default)]
+struct DiscordError {
+    message: Option<String>,
+    code: Option<u64>,
+    retry_after: Option<f64>,
+    #[serde(default)]
+    global: bool,
+}
+
+#[derive(Clone)]
 pub struct DiscordReceiver {
     pub config: ConfigFeedDiscordReceiver,
 }
@@ -11,9 +49,361 @@ pub struct DiscordReceiver {
 pub type JsonObject = serde_json::Map<String, serde_json::Value>;
 
 impl Receivable for DiscordReceiver {
-    async fn send_item(&self, item: &crate::database::DatabaseFeedItem) -> anyhow::Result<()> {
+    fn supports_digests(&self) -> bool {
+        true
+    }
+
+    fn supports_removal(&self, _removal: ConfigFeedReceiverRemoval) -> bool {
+        true
+    }
+
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        let (webhook_url, message) = self.build_message(item, MessageKind::New)?;
+
+        let mut url = Url::parse(&webhook_url)?;
+        url.query_pairs_mut().append_pair("wait", "true");
+
+        if let Some(thread_id) = &self.config.thread_id {
+            url.query_pairs_mut()
+                .append_pair("thread_id", &item.sub(thread_id));
+        }
+
+        let resp = execute_webhook(Method::POST, url, Some(&message))
+            .await?
+            .unwrap_or_default();
+
+        // the message can be edited through the webhook using its id, the webhook is stored by
+        // its id as its url contains the token
+        let (Some(id), Ok(webhook)) = (
+            resp.get("id").and_then(|id| id.as_str()),
+            webhook_id(&webhook_url),
+        ) else {
+            return Ok(None);
+        };
+
+        let mut message_ref = format!("{}/{}", webhook, id);
+
+        // messages in threads can only be edited by passing the thread, which is the channel
+        // the message was posted in
+        if self.config.thread_id.is_some() || self.config.thread_name.is_some() {
+            if let Some(channel_id) = resp.get("channel_id").and_then(|id| id.as_str()) {
+                message_ref.push('/');
+                message_ref.push_str(channel_id);
+            }
+        }
+
+        Ok(Some(message_ref))
+    }
+
+    fn batch_size(&self) -> Option<usize> {
+        self.config
+            .batch
+            .as_ref()
+            .map(|b| b.max_size.unwrap_or(MAX_EMBEDS).clamp(1, MAX_EMBEDS))
+    }
+
+    async fn send_batch(&self, items: &[DatabaseFeedItem]) -> Result<usize> {
+        let Some(first) = items.first() else {
+            return Ok(0);
+        };
+
+        let (webhook_url, mut message) = self.build_message(first, MessageKind::New)?;
+        let mut embeds = take_embeds(&mut message);
+        let mut roles = mentioned(&message, "roles");
+        let mut users = mentioned(&message, "users");
+        let mut count = 1;
+
+        // items are added as long as they go to the same webhook and their embeds fit
+        for item in items
+            .iter()
+            .skip(1)
+            .take(self.batch_size().unwrap_or(1) - 1)
+        {
+            let (url, mut next) = self.build_message(item, MessageKind::New)?;
+            let next_embeds = take_embeds(&mut next);
+
+            let length: usize = embeds.iter().chain(&next_embeds).map(embed_length).sum();
+            if url != webhook_url
+                || embeds.len() + next_embeds.len() > MAX_EMBEDS
+                || length > MAX_TOTAL_EMBED_LENGTH
+            {
+                break;
+            }
+
+            embeds.extend(next_embeds);
+            roles.extend(mentioned(&next, "roles"));
+            users.extend(mentioned(&next, "users"));
+            count += 1;
+        }
+
+        if count > 1 {
+            roles.sort();
+            roles.dedup();
+            users.sort();
+            users.dedup();
+
+            let mentions: Vec<String> = roles
+                .iter()
+                .map(|r| format!("<@&{}>", r))
+                .chain(users.iter().map(|u| format!("<@{}>", u)))
+                .collect();
+
+            let summary = self
+                .config
+                .batch
+                .as_ref()
+                .and_then(|b| b.content.as_ref())
+                .map(|c| {
+                    let mut item = first.clone();
+                    item.variables
+                        .insert(String::from("count"), count.to_string());
+                    item.sub(c)
+                });
+
+            let content = match (summary, mentions.is_empty()) {
+                (summary, true) => summary,
+                (Some(summary), false) => Some(format!("{} {}", mentions.join(" "), summary)),
+                (None, false) => Some(mentions.join(" ")),
+            };
+
+            match content {
+                Some(content) => message.insert(
+                    String::from("content"),
+                    Value::String(trunc(&content, MAX_CONTENT_LENGTH)),
+                ),
+                None => message.remove("content"),
+            };
+
+            message.insert(
+                String::from("allowed_mentions"),
+                json!({"parse": [], "roles": roles, "users": users}),
+            );
+        }
+
+        message.insert(String::from("embeds"), embeds.into());
+
+        let mut url = Url::parse(&webhook_url)?;
+
+        if let Some(thread_id) = &self.config.thread_id {
+            url.query_pairs_mut()
+                .append_pair("thread_id", &first.sub(thread_id));
+        }
+
+        execute_webhook(Method::POST, url, Some(&message)).await?;
+
+        Ok(count)
+    }
+
+    async fn send_digest(
+        &self,
+        digest: &ConfigFeedReceiverDigest,
+        items: &[DatabaseFeedItem],
+        sent: &mut usize,
+    ) -> Result<()> {
+        let Some(first) = items.first() else {
+            return Ok(());
+        };
+
+        let title = trunc(&digest_title(digest, items, str::to_owned), 256);
+        let line = digest.line.as_deref().unwrap_or(DEFAULT_DIGEST_LINE);
+
+        // the lines are split across embeds, and the embeds across messages
+        let descriptions = chunk_lines(
+            String::new(),
+            items
+                .iter()
+                .map(|item| trunc(&item.sub(line), MAX_DESCRIPTION_LENGTH)),
+            MAX_DESCRIPTION_LENGTH,
+        );
+
+        let mut messages: Vec<(Vec<Value>, usize)> = vec![(Vec::new(), 0)];
+        for (i, (description, count)) in descriptions.into_iter().enumerate() {
+            let mut embed = json!({"description": description});
+            if i == 0 {
+                embed["title"] = Value::String(title.clone());
+            }
+
+            let (embeds, message_count) = messages.last_mut().unwrap();
+            let length: usize = embeds.iter().map(embed_length).sum();
+
+            if !embeds.is_empty()
+                && (embeds.len() >= MAX_EMBEDS
+                    || length + embed_length(&embed) > MAX_TOTAL_EMBED_LENGTH)
+            {
+                messages.push((vec![embed], count));
+            } else {
+                embeds.push(embed);
+                *message_count = count;
+            }
+        }
+
+        let mentions: Vec<String> = self
+            .config
+            .mention_roles
+            .iter()
+            .map(|r| format!("<@&{}>", r))
+            .chain(
+                self.config
+                    .mention_users
+                    .iter()
+                    .map(|u| format!("<@{}>", u)),
+            )
+            .collect();
+
+        let mut thread_id = self.config.thread_id.as_ref().map(|t| first.sub(t));
+
+        for (i, (embeds, count)) in messages.into_iter().enumerate() {
+            let mut message = JsonObject::new();
+
+            if i == 0 && !mentions.is_empty() {
+                message.insert(String::from("content"), Value::String(mentions.join(" ")));
+            }
+
+            message.insert(
+                String::from("allowed_mentions"),
+                json!({
+                    "parse": [],
+                    "roles": self.config.mention_roles,
+                    "users": self.config.mention_users
+                }),
+            );
+
+            if let Some(username) = &self.config.username {
+                message.insert(
+                    String::from("username"),
+                    Value::String(trunc(&first.sub(username), 80)),
+                );
+            }
+
+            if let Some(avatar_url) = &self.config.avatar_url {
+                message.insert(
+                    String::from("avatar_url"),
+                    Value::String(first.sub(avatar_url)),
+                );
+            }
+
+            // the digest creates a single forum post, the remaining messages are posted in it
+            if thread_id.is_none() && self.config.thread_name.is_some() {
+                message.insert(
+                    String::from("thread_name"),
+                    Value::String(trunc(&title, 100)),
+                );
+            }
+
+            message.insert(String::from("embeds"), embeds.into());
+
+            let mut url = Url::parse(&self.config.webhook_url)?;
+            url.query_pairs_mut().append_pair("wait", "true");
+
+            if let Some(thread_id) = &thread_id {
+                url.query_pairs_mut().append_pair("thread_id", thread_id);
+            }
+
+            let resp = execute_webhook(Method::POST, url, Some(&message))
+                .await?
+                .unwrap_or_default();
+            *sent = count;
+
+            if self.config.thread_name.is_some() {
+                thread_id = thread_id.or(resp
+                    .get("channel_id")
+                    .and_then(|id| id.as_str())
+                    .map(String::from));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        let (_, message) = self.build_message(item, MessageKind::Updated)?;
+
+        execute_webhook(
+            Method::PATCH,
+            self.message_url(message_ref)?,
+            Some(&message),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn retract_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        let (_, message) = self.build_message(item, MessageKind::Retracted)?;
+
+        execute_webhook(
+            Method::PATCH,
+            self.message_url(message_ref)?,
+            Some(&message),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_item(&self, message_ref: &str) -> Result<()> {
+        execute_webhook(Method::DELETE, self.message_url(message_ref)?, None).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MessageKind {
+    New,
+    Updated,
+    Retracted,
+}
+
+impl DiscordReceiver {
+    pub fn new(config: &ConfigFeedDiscordReceiver) -> Self {
+        DiscordReceiver {
+            config: config.clone(),
+        }
+    }
+
+    /// Url of the message through the configured webhook it was sent with
+    fn message_url(&self, message_ref: &str) -> Result<Url> {
+        let invalid = || PermanentError(format!("invalid message reference {}", message_ref));
+
+        let mut parts = message_ref.split('/');
+        let webhook = parts.next().ok_or_else(invalid)?;
+        let message_id = parts.next().ok_or_else(invalid)?;
+
+        let webhook_url = std::iter::once(&self.config.webhook_url)
+            .chain(
+                self.config
+                    .overrides
+                    .iter()
+                    .filter_map(|o| o.webhook_url.as_ref()),
+            )
+            .find(|url| webhook_id(url).is_ok_and(|id| id == webhook))
+            .ok_or_else(|| {
+                PermanentError(format!("webhook {} is no longer configured", webhook))
+            })?;
+
+        let mut url = Url::parse(webhook_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid webhook url"))?
+            .extend(["messages", message_id]);
+
+        if let Some(thread_id) = parts.next() {
+            url.query_pairs_mut().append_pair("thread_id", thread_id);
+        }
+
+        Ok(url)
+    }
+
+    /// Renders the message for the item, returns the webhook url after applying the overrides
+    fn build_message(
+        &self,
+        item: &DatabaseFeedItem,
+        kind: MessageKind,
+    ) -> Result<(String, JsonObject)> {
         let mut webhook_url = self.config.webhook_url.clone();
         let mut content = self.config.content.clone();
+        let mut roles = self.config.mention_roles.clone();
+        let mut users = self.config.mention_users.clone();
         for or in &self.config.overrides {
             let Some(value) = item.variables.get(&or.field) else {
                 continue;
@@ -30,13 +420,95 @@ impl Receivable for DiscordReceiver {
             if let Some(c) = or.content.clone() {
                 content = Some(c);
             }
-            
+
+            roles.extend(or.mention_roles.iter().cloned());
+            users.extend(or.mention_users.iter().cloned());
+        }
+
+        roles.sort();
+        roles.dedup();
+        users.sort();
+        users.dedup();
+
+        let marker = match kind {
+            MessageKind::New => None,
+            MessageKind::Updated => self.config.updated_marker.clone(),
+            MessageKind::Retracted => Some(
+                self.config
+                    .retracted_marker
+                    .clone()
+                    .unwrap_or(String::from(DEFAULT_RETRACTED_MARKER)),
+            ),
+        };
+
+        if let Some(marker) = marker {
+            content = Some(match content {
+                Some(content) => format!("{} {}", marker, content),
+                None => marker,
+            });
         }
 
         let mut message = JsonObject::new();
 
-        if let Some(content) = &content {
-            message.insert(String::from("content"), Value::String(item.sub(content)));
+        let mentions: Vec<String> = roles
+            .iter()
+            .map(|r| format!("<@&{}>", r))
+            .chain(users.iter().map(|u| format!("<@{}>", u)))
+            .collect();
+
+        let content = match (content.map(|c| item.sub(&c)), mentions.is_empty()) {
+            (content, true) => content,
+            (Some(content), false) => Some(format!("{} {}", mentions.join(" "), content)),
+            (None, false) => Some(mentions.join(" ")),
+        };
+
+        if let Some(content) = content {
+            message.insert(
+                String::from("content"),
+                Value::String(trunc(&content, MAX_CONTENT_LENGTH)),
+            );
+        }
+
+        // feed content could contain @everyone or role mentions, only the configured ones ping
+        message.insert(
+            String::from("allowed_mentions"),
+            json!({"parse": [], "roles": roles, "users": users}),
+        );
+
+        if let Some(username) = &self.config.username {
+            message.insert(
+                String::from("username"),
+                Value::String(trunc(&item.sub(username), 80)),
+            );
+        }
+
+        if let Some(avatar_url) = &self.config.avatar_url {
+            message.insert(
+                String::from("avatar_url"),
+                Value::String(item.sub(avatar_url)),
+            );
+        }
+
+        // thread_name and applied_tags can only be set when creating the forum post
+        if let (MessageKind::New, Some(thread_name)) = (kind, &self.config.thread_name) {
+            message.insert(
+                String::from("thread_name"),
+                Value::String(trunc(&item.sub(thread_name), 100)),
+            );
+
+            let applied_tags: Vec<&String> = item
+                .variables
+                .get("categories")
+                .map(|c| c.split(", ").collect::<Vec<&str>>())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|c| self.config.forum_tags.get(*c))
+                .take(5)
+                .collect();
+
+            if !applied_tags.is_empty() {
+                message.insert(String::from("applied_tags"), json!(applied_tags));
+            }
         }
 
         let embeds: Vec<JsonObject> = self
@@ -47,10 +519,11 @@ impl Receivable for DiscordReceiver {
                 let mut embed = JsonObject::new();
 
                 if let Some(title) = e.title.clone() {
-                    embed.insert(
-                        String::from("title"),
-                        Value::String(trunc(&item.sub(&title), 256)),
-                    );
+                    let title = match kind {
+                        MessageKind::Retracted => format!("~~{}~~", trunc(&item.sub(&title), 252)),
+                        _ => trunc(&item.sub(&title), 256),
+                    };
+                    embed.insert(String::from("title"), Value::String(title));
                 }
 
                 if let Some(description) = e.description.clone() {
@@ -76,9 +549,37 @@ impl Receivable for DiscordReceiver {
                 }
 
                 if let Some(footer) = e.footer.clone() {
+                    let mut footer = json!({"text": trunc(&item.sub(&footer), 2048)});
+
+                    if let Some(icon_url) = &e.footer_icon_url {
+                        footer["icon_url"] = Value::String(item.sub(icon_url));
+                    }
+
+                    embed.insert(String::from("footer"), footer);
+                }
+
+                if let Some(color) = e.color.as_ref().and_then(|c| parse_color(&item.sub(c))) {
+                    embed.insert(String::from("color"), color.into());
+                }
+
+                if let Some(author) = &e.author {
+                    let mut value = json!({"name": trunc(&item.sub(&author.name), 256)});
+
+                    if let Some(url) = &author.url {
+                        value["url"] = Value::String(item.sub(url));
+                    }
+
+                    if let Some(icon_url) = &author.icon_url {
+                        value["icon_url"] = Value::String(item.sub(icon_url));
+                    }
+
+                    embed.insert(String::from("author"), value);
+                }
+
+                if e.timestamp {
                     embed.insert(
-                        String::from("footer"),
-                        json!({"text": trunc(&item.sub(&footer), 2048)}),
+                        String::from("timestamp"),
+                        Value::String(item.published_at.to_rfc3339()),
                     );
                 }
 
@@ -102,27 +603,363 @@ impl Receivable for DiscordReceiver {
 
         message.insert(String::from("embeds"), embeds.into());
 
-        dbg!(&message);
+        enforce_limits(&mut message);
 
-        reqwest::Client::new()
-            .post(webhook_url)
-            .json(&message)
-            .send()
-            .await?;
-        Ok(())
+        Ok((webhook_url, message))
     }
 }
 
-impl DiscordReceiver {
-    pub fn new(config: &ConfigFeedDiscordReceiver) -> Self {
-        DiscordReceiver {
-            config: config.clone(),
+/// Id of the webhook, the segment of its url before the token
+fn webhook_id(webhook_url: &str) -> Result<String> {
+    let url = Url::parse(webhook_url)?;
+    let mut segments = url
+        .path_segments()
+        .ok_or_else(|| anyhow!("invalid webhook url"))?;
+
+    segments
+        .find(|s| *s == "webhooks")
+        .and_then(|_| segments.next())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("invalid webhook url"))
+}
+
+/// Executes the webhook request, waiting for the rate limit of the webhook which is shared by
+/// all feeds, returns the response body if there is one
+async fn execute_webhook(
+    method: Method,
+    url: Url,
+    message: Option<&JsonObject>,
+) -> Result<Option<Value>> {
+    let client = reqwest::Client::new();
+    let bucket = rate_limit_bucket(&url);
+
+    retry_rate_limited("Discord", || {
+        execute_webhook_once(&client, &method, &url, message, &bucket)
+    })
+    .await
+}
+
+async fn execute_webhook_once(
+    client: &reqwest::Client,
+    method: &Method,
+    url: &Url,
+    message: Option<&JsonObject>,
+    bucket: &str,
+) -> Result<Attempt<Option<Value>>> {
+    wait_for_rate_limit(bucket).await;
+
+    let mut req = client.request(method.clone(), url.clone());
+
+    if let Some(message) = message {
+        req = req.json(message);
+    }
+
+    let resp = req.send().await?;
+
+    update_rate_limit(bucket, resp.headers());
+
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(Attempt::Done(resp.json().await.ok()));
+    }
+
+    // a message deleted by hand is already gone
+    if method == Method::DELETE && status == StatusCode::NOT_FOUND {
+        return Ok(Attempt::Done(None));
+    }
+
+    let body: DiscordError = resp.json().await.unwrap_or_default();
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        // other requests to the webhook, or to any webhook when global, wait as well
+        let retry_after = Duration::from_secs_f64(body.retry_after.unwrap_or(1.0).max(0.0));
+        limit_until(
+            if body.global { GLOBAL_BUCKET } else { bucket },
+            Instant::now() + retry_after,
+        );
+        return Ok(Attempt::RateLimited(retry_after));
+    }
+
+    let error = format!(
+        "Discord responded with {}: {} (code {})",
+        status,
+        body.message.unwrap_or_default(),
+        body.code.unwrap_or_default()
+    );
+
+    // client errors like an invalid form body or a deleted webhook will never succeed
+    if status.is_client_error() {
+        return Err(PermanentError(error).into());
+    }
+
+    Err(anyhow!(error))
+}
+
+/// Requests to the messages of a webhook share the rate limit of the webhook itself
+fn rate_limit_bucket(url: &Url) -> String {
+    let path = url.path();
+    let path = path.split("/messages/").next().unwrap_or(path);
+    format!("{}{}", url.host_str().unwrap_or_default(), path)
+}
+
+async fn wait_for_rate_limit(bucket: &str) {
+    let until = {
+        let limits = RATE_LIMITS.lock().unwrap();
+        [GLOBAL_BUCKET, bucket]
+            .iter()
+            .filter_map(|k| limits.get(*k))
+            .max()
+            .copied()
+    };
+
+    if let Some(until) = until {
+        tokio::time::sleep_until(until.into()).await;
+    }
+}
+
+fn update_rate_limit(bucket: &str, headers: &HeaderMap) {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<f64>().ok())
+    };
+
+    if let (Some(remaining), Some(reset_after)) = (
+        header("x-ratelimit-remaining"),
+        header("x-ratelimit-reset-after"),
+    ) {
+        if remaining < 1.0 {
+            limit_until(
+                bucket,
+                Instant::now() + Duration::from_secs_f64(reset_after.max(0.0)),
+            );
         }
     }
 }
 
-fn trunc(input: &str, len: usize) -> String {
-    let mut str = input.to_owned();
-    str.truncate(len);
-    str
+fn limit_until(bucket: &str, until: Instant) {
+    let mut limits = RATE_LIMITS.lock().unwrap();
+    limits.retain(|_, u| *u > Instant::now());
+    let current = limits.entry(bucket.to_owned()).or_insert(until);
+    *current = (*current).max(until);
+}
+
+/// Parses `#rrggbb`, `0xrrggbb` or a decimal color, invalid colors are left out
+fn parse_color(input: &str) -> Option<u32> {
+    let input = input.trim();
+
+    let color = match input.strip_prefix('#').or_else(|| input.strip_prefix("0x")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => input.parse().ok()?,
+    };
+
+    (color <= 0xFFFFFF).then_some(color)
+}
+
+fn take_embeds(message: &mut JsonObject) -> Vec<Value> {
+    match message.remove("embeds") {
+        Some(Value::Array(embeds)) => embeds,
+        _ => Vec::new(),
+    }
+}
+
+/// Role or user ids allowed to be mentioned by the message
+fn mentioned(message: &JsonObject, kind: &str) -> Vec<String> {
+    message
+        .get("allowed_mentions")
+        .and_then(|m| m[kind].as_array())
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|id| id.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn char_count(value: &Value) -> usize {
+    value.as_str().map(|s| s.chars().count()).unwrap_or(0)
+}
+
+/// Characters counted towards the total embed limit
+fn embed_length(embed: &Value) -> usize {
+    char_count(&embed["title"])
+        + char_count(&embed["description"])
+        + char_count(&embed["footer"]["text"])
+        + char_count(&embed["author"]["name"])
+        + embed["fields"]
+            .as_array()
+            .map(|fields| {
+                fields
+                    .iter()
+                    .map(|f| char_count(&f["name"]) + char_count(&f["value"]))
+                    .sum()
+            })
+            .unwrap_or(0)
+}
+
+/// Enforces the limits on the amount of embeds and fields and the total length of the embeds,
+/// descriptions are shrunk proportionally before fields and embeds are dropped
+fn enforce_limits(message: &mut JsonObject) {
+    let Some(embeds) = message.get_mut("embeds").and_then(|e| e.as_array_mut()) else {
+        return;
+    };
+
+    embeds.truncate(MAX_EMBEDS);
+
+    for embed in embeds.iter_mut() {
+        if let Some(fields) = embed.get_mut("fields").and_then(Value::as_array_mut) {
+            fields.truncate(MAX_FIELDS);
+        }
+    }
+
+    let total: usize = embeds.iter().map(embed_length).sum();
+
+    if total <= MAX_TOTAL_EMBED_LENGTH {
+        return;
+    }
+
+    let excess = total - MAX_TOTAL_EMBED_LENGTH;
+    let descriptions: usize = embeds.iter().map(|e| char_count(&e["description"])).sum();
+
+    if descriptions > 0 {
+        for embed in embeds.iter_mut() {
+            let len = char_count(&embed["description"]);
+            if len == 0 {
+                continue;
+            }
+
+            let shrink = (excess * len).div_ceil(descriptions);
+            let description = trunc(
+                embed["description"].as_str().unwrap_or_default(),
+                len.saturating_sub(shrink),
+            );
+            embed["description"] = Value::String(description);
+        }
+    }
+
+    // the descriptions were not enough, drop fields and then embeds from the end
+    while embeds.iter().map(embed_length).sum::<usize>() > MAX_TOTAL_EMBED_LENGTH {
+        let Some(last) = embeds.last_mut() else {
+            break;
+        };
+
+        match last.get_mut("fields").and_then(Value::as_array_mut) {
+            Some(fields) if !fields.is_empty() => {
+                fields.pop();
+            }
+            _ => {
+                embeds.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(embeds: Vec<Value>) -> JsonObject {
+        let mut message = JsonObject::new();
+        message.insert(String::from("embeds"), embeds.into());
+        message
+    }
+
+    fn embeds(message: &JsonObject) -> &Vec<Value> {
+        message["embeds"].as_array().unwrap()
+    }
+
+    fn total(message: &JsonObject) -> usize {
+        embeds(message).iter().map(embed_length).sum()
+    }
+
+    #[test]
+    fn keeps_message_at_the_limits() {
+        let fields: Vec<Value> = (0..MAX_FIELDS)
+            .map(|_| json!({"name": "n", "value": "v"}))
+            .collect();
+        let mut embeds_at_limit: Vec<Value> = (0..MAX_EMBEDS - 1)
+            .map(|_| json!({"title": "x".repeat(500)}))
+            .collect();
+        embeds_at_limit.push(json!({"description": "d".repeat(1450), "fields": fields}));
+
+        let mut msg = message(embeds_at_limit.clone());
+        assert_eq!(total(&msg), MAX_TOTAL_EMBED_LENGTH);
+
+        enforce_limits(&mut msg);
+        assert!(embeds(&msg) == &embeds_at_limit);
+    }
+
+    #[test]
+    fn drops_embeds_and_fields_over_the_count() {
+        let fields: Vec<Value> = (0..MAX_FIELDS + 1)
+            .map(|_| json!({"name": "n", "value": "v"}))
+            .collect();
+        let mut msg = message(
+            (0..MAX_EMBEDS + 1)
+                .map(|_| json!({"title": "t", "fields": fields}))
+                .collect(),
+        );
+
+        enforce_limits(&mut msg);
+
+        assert_eq!(embeds(&msg).len(), MAX_EMBEDS);
+        assert!(embeds(&msg)
+            .iter()
+            .all(|e| e["fields"].as_array().unwrap().len() == MAX_FIELDS));
+    }
+
+    #[test]
+    fn shrinks_descriptions_proportionally() {
+        let mut msg = message(vec![
+            json!({"title": "t", "description": "a".repeat(4000)}),
+            json!({"description": "b".repeat(2000)}),
+        ]);
+        assert_eq!(total(&msg), MAX_TOTAL_EMBED_LENGTH + 1);
+
+        enforce_limits(&mut msg);
+
+        let first = char_count(&embeds(&msg)[0]["description"]);
+        let second = char_count(&embeds(&msg)[1]["description"]);
+        assert!(total(&msg) <= MAX_TOTAL_EMBED_LENGTH);
+        assert_eq!((first, second), (3999, 1999));
+        assert!(embeds(&msg)[0]["description"]
+            .as_str()
+            .unwrap()
+            .ends_with('…'));
+    }
+
+    #[test]
+    fn drops_fields_from_the_last_embed_first() {
+        let field = json!({"name": "n".repeat(256), "value": "v".repeat(1024)});
+        let mut msg = message(vec![
+            json!({"title": "t", "fields": [field, field]}),
+            json!({"title": "t", "fields": [field, field, field]}),
+        ]);
+        assert_eq!(total(&msg), 6402);
+
+        enforce_limits(&mut msg);
+
+        assert_eq!(total(&msg), 5122);
+        assert_eq!(embeds(&msg).len(), 2);
+        assert_eq!(embeds(&msg)[0]["fields"].as_array().unwrap().len(), 2);
+        assert_eq!(embeds(&msg)[1]["fields"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn drops_embeds_without_fields_or_descriptions() {
+        let field = json!({"name": "n".repeat(256), "value": "v".repeat(1024)});
+        let mut msg = message(vec![
+            json!({"title": "t", "fields": [field, field, field, field]}),
+            json!({"title": "t".repeat(256), "footer": {"text": "f".repeat(1000)}}),
+        ]);
+        assert_eq!(total(&msg), 6377);
+
+        enforce_limits(&mut msg);
+
+        assert_eq!(embeds(&msg).len(), 1);
+        assert_eq!(total(&msg), 5121);
+        assert!(embeds(&msg)[0].get("description").is_none());
+    }
 }
@@ -0,0 +1,465 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use lettre::{
+    message::{Mailbox, MessageBuilder, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::{
+    config::{
+        ConfigFeedEmailReceiver, ConfigFeedEmailTls, ConfigFeedReceiverDigest,
+        ConfigFeedReceiverRemoval,
+    },
+    database::DatabaseFeedItem,
+    markdown::{escape_html, html_to_text},
+};
+
+use super::{digest_title, stable_id, PermanentError, Receivable};
+
+const DEFAULT_SUBJECT: &str = "$title";
+const DEFAULT_TEXT: &str = "$title\n$link";
+const DEFAULT_HTML: &str = "<p><a href=\"$link\">$title</a></p>";
+const DEFAULT_DIGEST_LINE: &str = "<a href=\"$link\">$title</a>";
+
+#[derive(Clone)]
+pub struct EmailReceiver {
+    pub config: ConfigFeedEmailReceiver,
+}
+
+impl Receivable for EmailReceiver {
+    fn supports_digests(&self) -> bool {
+        true
+    }
+
+    // sent emails can't be taken back, a retraction is sent in reply instead
+    fn supports_removal(&self, removal: ConfigFeedReceiverRemoval) -> bool {
+        removal != ConfigFeedReceiverRemoval::Delete
+    }
+
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        // retries of the same item reuse the id, so mail clients can recognize duplicates
+        let message_id = self.message_id(&item.feed_name, &[&item.external_id])?;
+
+        let message = self
+            .builder(&self.subject(item))?
+            .message_id(Some(message_id.clone()))
+            .references(self.feed_root_id(&item.feed_name)?)
+            .multipart(self.body(item))?;
+
+        self.send(message).await?;
+
+        // updates and retractions reply to the message, so mail clients show them as a thread
+        Ok(Some(message_id))
+    }
+
+    async fn send_digest(
+        &self,
+        digest: &ConfigFeedReceiverDigest,
+        items: &[DatabaseFeedItem],
+        _sent: &mut usize,
+    ) -> Result<()> {
+        let Some(first) = items.first() else {
+            return Ok(());
+        };
+
+        let title = digest_title(digest, items, str::to_owned);
+        let line = digest.line.as_deref().unwrap_or(DEFAULT_DIGEST_LINE);
+
+        let lines: String = items
+            .iter()
+            .map(|item| format!("<li>{}</li>", item.sub_escaped(line, escape_html)))
+            .collect();
+        let html = format!("<h2>{}</h2><ul>{}</ul>", escape_html(&title), lines);
+
+        // links are lost when converting the lines to text, so they are added after the line
+        let mut text = format!("{}\n", title);
+        for item in items {
+            text.push_str("\n- ");
+            text.push_str(&html_to_text(&item.sub_escaped(line, escape_html)));
+            if let Some(link) = item.variable("link").filter(|l| !l.is_empty()) {
+                text.push_str(&format!(" <{}>", link));
+            }
+        }
+
+        // digests of the same feed reference a common root, so they are threaded as well
+        let now = Utc::now().to_rfc3339();
+        let parts: Vec<&str> = items
+            .iter()
+            .map(|i| i.external_id.as_str())
+            .chain([now.as_str()])
+            .collect();
+        let message = self
+            .builder(&title)?
+            .message_id(Some(self.message_id(&first.feed_name, &parts)?))
+            .references(self.feed_root_id(&first.feed_name)?)
+            .multipart(MultiPart::alternative_plain_html(text, html))?;
+
+        self.send(message).await
+    }
+
+    async fn update_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        self.send_reply(item, message_ref, "Updated").await
+    }
+
+    async fn retract_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        self.send_reply(item, message_ref, "Retracted").await
+    }
+}
+
+impl EmailReceiver {
+    pub fn new(config: &ConfigFeedEmailReceiver) -> Self {
+        EmailReceiver {
+            config: config.clone(),
+        }
+    }
+
+    /// Subject of the item on a single line, as headers can't contain line breaks
+    fn subject(&self, item: &DatabaseFeedItem) -> String {
+        item.sub(self.config.subject.as_deref().unwrap_or(DEFAULT_SUBJECT))
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn body(&self, item: &DatabaseFeedItem) -> MultiPart {
+        MultiPart::alternative_plain_html(
+            item.sub(self.config.text.as_deref().unwrap_or(DEFAULT_TEXT)),
+            item.sub_escaped(
+                self.config.html.as_deref().unwrap_or(DEFAULT_HTML),
+                escape_html,
+            ),
+        )
+    }
+
+    fn builder(&self, subject: &str) -> Result<MessageBuilder> {
+        let mut builder = Message::builder()
+            .from(parse_mailbox(&self.config.from)?)
+            .subject(subject);
+
+        for to in &self.config.to {
+            builder = builder.to(parse_mailbox(to)?);
+        }
+
+        for cc in &self.config.cc {
+            builder = builder.cc(parse_mailbox(cc)?);
+        }
+
+        Ok(builder)
+    }
+
+    /// Sends a follow-up about the item in reply to the original message
+    async fn send_reply(
+        &self,
+        item: &DatabaseFeedItem,
+        message_ref: &str,
+        prefix: &str,
+    ) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let message = self
+            .builder(&format!("{}: {}", prefix, self.subject(item)))?
+            .message_id(Some(
+                self.message_id(&item.feed_name, &[message_ref, prefix, &now])?,
+            ))
+            .in_reply_to(message_ref.to_owned())
+            .references(format!(
+                "{} {}",
+                self.feed_root_id(&item.feed_name)?,
+                message_ref
+            ))
+            .multipart(self.body(item))?;
+
+        self.send(message).await
+    }
+
+    /// Message id of the feed at the domain of the sender, unique for the recipients and the
+    /// hashed parts
+    fn message_id(&self, feed_name: &str, parts: &[&str]) -> Result<String> {
+        let recipients = self.config.to.join(",");
+        let parts: Vec<&str> = [feed_name, &recipients]
+            .into_iter()
+            .chain(parts.iter().copied())
+            .collect();
+
+        Ok(format!(
+            "<{}.{}@{}>",
+            stable_id(&parts),
+            sanitize(feed_name),
+            self.domain()?
+        ))
+    }
+
+    /// Id of the feed that all messages about its items refer to
+    fn feed_root_id(&self, feed_name: &str) -> Result<String> {
+        Ok(format!("<{}@{}>", sanitize(feed_name), self.domain()?))
+    }
+
+    fn domain(&self) -> Result<String> {
+        Ok(parse_mailbox(&self.config.from)?.email.domain().to_owned())
+    }
+
+    async fn send(&self, message: Message) -> Result<()> {
+        let host = &self.config.host;
+
+        let mut builder = match self.config.tls {
+            ConfigFeedEmailTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+            }
+            ConfigFeedEmailTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            ConfigFeedEmailTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            }
+        };
+
+        if let Some(port) = self.config.port {
+            builder = builder.port(port);
+        }
+
+        if let (Some(username), Some(password)) = (&self.config.username, &self.config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        builder.build().send(message).await.map_err(|e| {
+            // 5xx replies like an unknown recipient or rejected credentials won't resolve
+            if e.is_permanent() {
+                PermanentError(format!("SMTP server rejected the message: {}", e)).into()
+            } else {
+                anyhow!("Failed to send email: {}", e)
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .map_err(|e| PermanentError(format!("invalid email address {}: {}", address, e)).into())
+}
+
+/// Feed names may contain characters that are not allowed in message ids
+fn sanitize(input: &str) -> String {
+    input
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::database::test_item;
+
+    /// Local SMTP server speaking just enough of the protocol for lettre, every accepted
+    /// message is passed on as the transcript of its envelope and data
+    async fn smtp_sink(rcpt_reply: &'static str) -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                let mut transcript = String::new();
+
+                write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let command = line.split([' ', ':']).next().unwrap_or_default();
+                    let reply = match command.to_ascii_uppercase().as_str() {
+                        "EHLO" => "250 localhost",
+                        "MAIL" | "RSET" => "250 OK",
+                        "RCPT" => rcpt_reply,
+                        "DATA" => "354 End data with <CR><LF>.<CR><LF>",
+                        "QUIT" => "221 Bye",
+                        _ => "502 Command not implemented",
+                    };
+
+                    transcript.push_str(&line);
+                    transcript.push('\n');
+                    write
+                        .write_all(format!("{}\r\n", reply).as_bytes())
+                        .await
+                        .unwrap();
+
+                    if command == "DATA" {
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if line == "." {
+                                break;
+                            }
+                            transcript.push_str(&line);
+                            transcript.push('\n');
+                        }
+
+                        // folded headers are unfolded, so they can be compared on a single line
+                        tx.send(std::mem::take(&mut transcript).replace("\n ", " "))
+                            .unwrap();
+                        write.write_all(b"250 OK queued\r\n").await.unwrap();
+                    }
+
+                    if command == "QUIT" {
+                        break;
+                    }
+                }
+            }
+        });
+
+        (port, rx)
+    }
+
+    fn receiver(port: u16) -> EmailReceiver {
+        EmailReceiver::new(&ConfigFeedEmailReceiver {
+            host: String::from("127.0.0.1"),
+            port: Some(port),
+            tls: ConfigFeedEmailTls::None,
+            username: None,
+            password: None,
+            from: String::from("Feeds <feeds@example.com>"),
+            to: vec![String::from("a@example.org"), String::from("b@example.org")],
+            cc: vec![String::from("c@example.org")],
+            subject: None,
+            text: None,
+            html: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn sends_items_with_a_stable_message_id() {
+        let (port, mut messages) = smtp_sink("250 OK").await;
+        let receiver = receiver(port);
+
+        let message_ref = receiver
+            .send_item(&test_item("1", "Fish & <chips>"))
+            .await
+            .unwrap()
+            .unwrap();
+        let message = messages.recv().await.unwrap();
+
+        let id = message_ref
+            .strip_prefix('<')
+            .and_then(|id| id.strip_suffix(".my-feed@example.com>"))
+            .unwrap();
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+
+        for line in [
+            "MAIL FROM:<feeds@example.com>",
+            "RCPT TO:<a@example.org>",
+            "RCPT TO:<b@example.org>",
+            "RCPT TO:<c@example.org>",
+            "Subject: Fish & <chips>",
+            "<p><a href=\"https://example.org/1\">Fish &amp; &lt;chips&gt;</a></p>",
+            &format!("Message-ID: {}", message_ref),
+            "References: <my-feed@example.com>",
+        ] {
+            assert!(
+                message.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                message
+            );
+        }
+
+        // a retry is recognizable as the same message, other items are not
+        let retried = receiver.send_item(&test_item("1", "Fish")).await.unwrap();
+        let other = receiver.send_item(&test_item("2", "Fish")).await.unwrap();
+        assert_eq!(retried.as_deref(), Some(message_ref.as_str()));
+        assert_ne!(other.as_deref(), Some(message_ref.as_str()));
+    }
+
+    #[tokio::test]
+    async fn replies_to_the_original_message() {
+        let (port, mut messages) = smtp_sink("250 OK").await;
+        let receiver = receiver(port);
+        let message_ref = "<0123456789abcdef0123456789abcdef.my-feed@example.com>";
+
+        receiver
+            .update_item(&test_item("1", "Title"), message_ref)
+            .await
+            .unwrap();
+        receiver
+            .retract_item(&test_item("1", "Title"), message_ref)
+            .await
+            .unwrap();
+
+        for prefix in ["Updated", "Retracted"] {
+            let message = messages.recv().await.unwrap();
+
+            for line in [
+                format!("Subject: {}: Title", prefix),
+                format!("In-Reply-To: {}", message_ref),
+                format!("References: <my-feed@example.com> {}", message_ref),
+            ] {
+                assert!(
+                    message.lines().any(|l| l == line),
+                    "{} in {}",
+                    line,
+                    message
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn threads_digests_under_the_feed() {
+        let (port, mut messages) = smtp_sink("250 OK").await;
+        let digest = ConfigFeedReceiverDigest {
+            schedule: None,
+            interval: Some(60),
+            title: None,
+            line: None,
+        };
+        let mut sent = 0;
+
+        receiver(port)
+            .send_digest(
+                &digest,
+                &[test_item("1", "A"), test_item("2", "B")],
+                &mut sent,
+            )
+            .await
+            .unwrap();
+        let message = messages.recv().await.unwrap();
+
+        for line in [
+            "Subject: 2 new items",
+            "References: <my-feed@example.com>",
+            "- A <https://example.org/1>",
+        ] {
+            assert!(
+                message.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                message
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_permanently_on_rejected_recipients() {
+        let (port, _) = smtp_sink("550 5.1.1 No such user").await;
+        let err = receiver(port)
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap_err();
+        assert!(err.is::<PermanentError>());
+
+        let (port, _) = smtp_sink("451 4.3.0 Try again later").await;
+        let err = receiver(port)
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap_err();
+        assert!(!err.is::<PermanentError>());
+    }
+}
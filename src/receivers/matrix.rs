@@ -0,0 +1,551 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::{Method, StatusCode, Url};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{ConfigFeedMatrixReceiver, ConfigFeedReceiverDigest, ConfigFeedReceiverRemoval},
+    database::DatabaseFeedItem,
+    markdown::{escape_html, html_to_text},
+};
+
+use super::{digest_title, retry_rate_limited, stable_id, Attempt, PermanentError, Receivable};
+
+const DEFAULT_MSGTYPE: &str = "m.text";
+const DEFAULT_BODY: &str = "$title $link";
+const DEFAULT_FORMATTED_BODY: &str = "<a href=\"$link\">$title</a>";
+const DEFAULT_DIGEST_LINE: &str = "<a href=\"$link\">$title</a>";
+
+/// Room ids of the aliases resolved so far, keyed by homeserver and alias
+static ROOM_IDS: LazyLock<Mutex<HashMap<String, String>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize, Default)]
+struct MatrixError {
+    errcode: Option<String>,
+    error: Option<String>,
+    retry_after_ms: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct MatrixReceiver {
+    pub config: ConfigFeedMatrixReceiver,
+}
+
+impl Receivable for MatrixReceiver {
+    fn supports_digests(&self) -> bool {
+        true
+    }
+
+    fn supports_removal(&self, removal: ConfigFeedReceiverRemoval) -> bool {
+        removal != ConfigFeedReceiverRemoval::Retract
+    }
+
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        let room_id = self.room_id().await?;
+        let content = self.build_content(item);
+
+        // retries of the same item reuse the transaction, so the homeserver ignores duplicates
+        let txn_id = transaction_id(&[&room_id, &item.feed_name, &item.external_id]);
+        let resp = self
+            .request(
+                Method::PUT,
+                &["rooms", &room_id, "send", "m.room.message", &txn_id],
+                Some(&content),
+                false,
+            )
+            .await?;
+
+        // the event can be edited and redacted through its id
+        Ok(resp["event_id"]
+            .as_str()
+            .map(|event_id| format!("{}/{}", room_id, event_id)))
+    }
+
+    async fn send_digest(
+        &self,
+        digest: &ConfigFeedReceiverDigest,
+        items: &[DatabaseFeedItem],
+        _sent: &mut usize,
+    ) -> Result<()> {
+        let Some(first) = items.first() else {
+            return Ok(());
+        };
+
+        let title = digest_title(digest, items, escape_html);
+        let line = digest.line.as_deref().unwrap_or(DEFAULT_DIGEST_LINE);
+
+        let lines: String = items
+            .iter()
+            .map(|item| format!("<li>{}</li>", item.sub_escaped(line, escape_html)))
+            .collect();
+        let formatted_body = format!("<p><strong>{}</strong></p><ul>{}</ul>", title, lines);
+
+        let content = json!({
+            "msgtype": self.msgtype(),
+            "body": html_to_text(&formatted_body),
+            "format": "org.matrix.custom.html",
+            "formatted_body": formatted_body
+        });
+
+        let room_id = self.room_id().await?;
+        let parts: Vec<&str> = [room_id.as_str(), &first.feed_name]
+            .into_iter()
+            .chain(items.iter().map(|i| i.external_id.as_str()))
+            .collect();
+        let txn_id = transaction_id(&parts);
+
+        self.request(
+            Method::PUT,
+            &["rooms", &room_id, "send", "m.room.message", &txn_id],
+            Some(&content),
+            false,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        let (room_id, event_id) = split_message_ref(message_ref)?;
+        let new_content = self.build_content(item);
+
+        // edits are new events replacing the original, clients without support for edits
+        // show the fallback prefixed with an asterisk
+        let mut content = new_content.clone();
+        content["body"] = Value::String(format!(
+            "* {}",
+            new_content["body"].as_str().unwrap_or_default()
+        ));
+        content["formatted_body"] = Value::String(format!(
+            "* {}",
+            new_content["formatted_body"].as_str().unwrap_or_default()
+        ));
+        content["m.new_content"] = new_content;
+        content["m.relates_to"] = json!({"rel_type": "m.replace", "event_id": event_id});
+
+        let txn_id = transaction_id(&[event_id, &content.to_string()]);
+
+        self.request(
+            Method::PUT,
+            &["rooms", room_id, "send", "m.room.message", &txn_id],
+            Some(&content),
+            false,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_item(&self, message_ref: &str) -> Result<()> {
+        let (room_id, event_id) = split_message_ref(message_ref)?;
+        let txn_id = transaction_id(&["redact", event_id]);
+
+        // an event that was redacted by hand is already gone
+        self.request(
+            Method::PUT,
+            &["rooms", room_id, "redact", event_id, &txn_id],
+            Some(&json!({"reason": "Removed from the feed"})),
+            true,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl MatrixReceiver {
+    pub fn new(config: &ConfigFeedMatrixReceiver) -> Self {
+        MatrixReceiver {
+            config: config.clone(),
+        }
+    }
+
+    fn msgtype(&self) -> &str {
+        self.config.msgtype.as_deref().unwrap_or(DEFAULT_MSGTYPE)
+    }
+
+    fn build_content(&self, item: &DatabaseFeedItem) -> Value {
+        json!({
+            "msgtype": self.msgtype(),
+            "body": item.sub(self.config.body.as_deref().unwrap_or(DEFAULT_BODY)),
+            "format": "org.matrix.custom.html",
+            "formatted_body": item.sub_escaped(
+                self.config.formatted_body.as_deref().unwrap_or(DEFAULT_FORMATTED_BODY),
+                escape_html,
+            )
+        })
+    }
+
+    /// Id of the configured room, resolving the alias when an alias is configured
+    async fn room_id(&self) -> Result<String> {
+        if !self.config.room.starts_with('#') {
+            return Ok(self.config.room.clone());
+        }
+
+        let key = format!("{}{}", self.config.homeserver_url, self.config.room);
+
+        if let Some(room_id) = ROOM_IDS.lock().unwrap().get(&key) {
+            return Ok(room_id.clone());
+        }
+
+        let resp = self
+            .request(
+                Method::GET,
+                &["directory", "room", &self.config.room],
+                None,
+                false,
+            )
+            .await?;
+
+        let room_id = resp["room_id"]
+            .as_str()
+            .ok_or(anyhow!("no room id for alias {}", self.config.room))?
+            .to_owned();
+
+        ROOM_IDS.lock().unwrap().insert(key, room_id.clone());
+
+        Ok(room_id)
+    }
+
+    /// Calls the client-server API, waiting as long as the homeserver asks when rate limited. A
+    /// missing resource gives `Null` when `missing_ok` is set
+    async fn request(
+        &self,
+        method: Method,
+        path: &[&str],
+        body: Option<&Value>,
+        missing_ok: bool,
+    ) -> Result<Value> {
+        let mut url = Url::parse(&self.config.homeserver_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid homeserver url"))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(path);
+
+        let client = reqwest::Client::new();
+
+        retry_rate_limited("Matrix", || {
+            self.request_once(&client, &method, &url, body, missing_ok)
+        })
+        .await
+    }
+
+    async fn request_once(
+        &self,
+        client: &reqwest::Client,
+        method: &Method,
+        url: &Url,
+        body: Option<&Value>,
+        missing_ok: bool,
+    ) -> Result<Attempt<Value>> {
+        let mut req = client
+            .request(method.clone(), url.clone())
+            .bearer_auth(&self.config.access_token);
+
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+
+        let resp = req.send().await?;
+        let status = resp.status();
+
+        if status.is_success() {
+            return Ok(Attempt::Done(resp.json().await.unwrap_or_default()));
+        }
+
+        if missing_ok && status == StatusCode::NOT_FOUND {
+            return Ok(Attempt::Done(Value::Null));
+        }
+
+        let error: MatrixError = resp.json().await.unwrap_or_default();
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Ok(Attempt::RateLimited(Duration::from_millis(
+                error.retry_after_ms.unwrap_or(1000),
+            )));
+        }
+
+        let error = format!(
+            "Matrix responded with {}: {} ({})",
+            status,
+            error.error.unwrap_or_default(),
+            error.errcode.unwrap_or_default()
+        );
+
+        // client errors like an invalid token or a room the user is not in won't resolve
+        if status.is_client_error() {
+            return Err(PermanentError(error).into());
+        }
+
+        Err(anyhow!(error))
+    }
+}
+
+/// Transaction id of the parts, the homeserver ignores a retry with the same id
+fn transaction_id(parts: &[&str]) -> String {
+    format!("rss2discord.{}", stable_id(parts))
+}
+
+/// Room ids can't contain a slash, so the first one separates the room from the event
+fn split_message_ref(message_ref: &str) -> Result<(&str, &str)> {
+    message_ref
+        .split_once('/')
+        .ok_or(PermanentError(format!("invalid message reference {}", message_ref)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server};
+
+    use super::*;
+    use crate::database::test_item;
+
+    fn receiver(homeserver_url: &str, room: &str) -> MatrixReceiver {
+        MatrixReceiver::new(&ConfigFeedMatrixReceiver {
+            homeserver_url: homeserver_url.to_owned(),
+            access_token: String::from("token"),
+            room: room.to_owned(),
+            msgtype: None,
+            body: None,
+            formatted_body: None,
+        })
+    }
+
+    fn send_path(room_id: &str) -> Matcher {
+        Matcher::Regex(format!(
+            r"^/_matrix/client/v3/rooms/{}/send/m\.room\.message/rss2discord\.[0-9a-f]{{32}}$",
+            room_id
+        ))
+    }
+
+    #[tokio::test]
+    async fn sends_items_escaped() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("PUT", send_path("!room:example.org"))
+            .match_header("authorization", "Bearer token")
+            .match_body(Matcher::Json(json!({
+                "msgtype": "m.text",
+                "body": "Fish & <chips> https://example.org/1",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<a href=\"https://example.org/1\">Fish &amp; &lt;chips&gt;</a>"
+            })))
+            .with_body(r#"{"event_id": "$event"}"#)
+            .create_async()
+            .await;
+
+        let message_ref = receiver(&server.url(), "!room:example.org")
+            .send_item(&test_item("1", "Fish & <chips>"))
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+        assert_eq!(message_ref.as_deref(), Some("!room:example.org/$event"));
+    }
+
+    #[tokio::test]
+    async fn reuses_the_transaction_of_an_item() {
+        let mut server = Server::new_async().await;
+        let path = format!(
+            "/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/{}",
+            transaction_id(&["!room:example.org", "my feed", "1"])
+        );
+        let mock = server
+            .mock("PUT", path.as_str())
+            .with_body(r#"{"event_id": "$event"}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        // a retry after a lost response repeats the transaction, updated titles included
+        let receiver = receiver(&server.url(), "!room:example.org");
+        receiver.send_item(&test_item("1", "Title")).await.unwrap();
+        receiver
+            .send_item(&test_item("1", "New title"))
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn resolves_aliases_once() {
+        let mut server = Server::new_async().await;
+        let alias = server
+            .mock(
+                "GET",
+                "/_matrix/client/v3/directory/room/%23news:example.org",
+            )
+            .with_body(r#"{"room_id": "!resolved:example.org", "servers": []}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let send = server
+            .mock("PUT", send_path("!resolved:example.org"))
+            .with_body(r#"{"event_id": "$event"}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let receiver = receiver(&server.url(), "#news:example.org");
+        receiver.send_item(&test_item("1", "Title")).await.unwrap();
+        let message_ref = receiver.send_item(&test_item("2", "Title")).await.unwrap();
+
+        alias.assert_async().await;
+        send.assert_async().await;
+        assert_eq!(message_ref.as_deref(), Some("!resolved:example.org/$event"));
+    }
+
+    #[tokio::test]
+    async fn edits_with_a_replacement_event() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("PUT", send_path("!room:example.org"))
+            .match_body(Matcher::PartialJson(json!({
+                "body": "* New https://example.org/1",
+                "m.new_content": {"body": "New https://example.org/1"},
+                "m.relates_to": {"rel_type": "m.replace", "event_id": "$event"}
+            })))
+            .with_body(r#"{"event_id": "$edit"}"#)
+            .create_async()
+            .await;
+
+        receiver(&server.url(), "!room:example.org")
+            .update_item(&test_item("1", "New"), "!room:example.org/$event")
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn redacts_events_that_may_be_gone() {
+        let mut server = Server::new_async().await;
+        let path = Matcher::Regex(String::from(
+            r"^/_matrix/client/v3/rooms/!room:example.org/redact/\$event/rss2discord\.[0-9a-f]{32}$",
+        ));
+        let redacted = server
+            .mock("PUT", path.clone())
+            .with_body(r#"{"event_id": "$redaction"}"#)
+            .create_async()
+            .await;
+
+        let receiver = receiver(&server.url(), "!room:example.org");
+        receiver
+            .delete_item("!room:example.org/$event")
+            .await
+            .unwrap();
+        redacted.assert_async().await;
+        redacted.remove_async().await;
+
+        let missing = server
+            .mock("PUT", path)
+            .with_status(404)
+            .with_body(r#"{"errcode": "M_NOT_FOUND", "error": "Event not found"}"#)
+            .create_async()
+            .await;
+
+        receiver
+            .delete_item("!room:example.org/$event")
+            .await
+            .unwrap();
+        missing.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn retries_when_rate_limited() {
+        let mut server = Server::new_async().await;
+        let limited = server
+            .mock("PUT", send_path("!room:example.org"))
+            .with_status(429)
+            .with_body(r#"{"errcode": "M_LIMIT_EXCEEDED", "retry_after_ms": 10}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let sent = server
+            .mock("PUT", send_path("!room:example.org"))
+            .with_body(r#"{"event_id": "$event"}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let message_ref = receiver(&server.url(), "!room:example.org")
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap();
+
+        limited.assert_async().await;
+        sent.assert_async().await;
+        assert_eq!(message_ref.as_deref(), Some("!room:example.org/$event"));
+    }
+
+    #[tokio::test]
+    async fn fails_permanently_on_client_errors() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("PUT", send_path("!room:example.org"))
+            .with_status(403)
+            .with_body(r#"{"errcode": "M_FORBIDDEN", "error": "Not in room"}"#)
+            .create_async()
+            .await;
+        server
+            .mock("PUT", send_path("!other:example.org"))
+            .with_status(502)
+            .create_async()
+            .await;
+
+        let err = receiver(&server.url(), "!room:example.org")
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap_err();
+        assert!(err.is::<PermanentError>());
+        assert!(err.to_string().contains("Not in room (M_FORBIDDEN)"));
+
+        let err = receiver(&server.url(), "!other:example.org")
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap_err();
+        assert!(!err.is::<PermanentError>());
+    }
+
+    #[tokio::test]
+    async fn sends_digests_as_one_list() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("PUT", send_path("!room:example.org"))
+            .match_body(Matcher::PartialJson(json!({
+                "formatted_body": "<p><strong>2 new items</strong></p><ul>\
+                    <li><a href=\"https://example.org/1\">A &amp; B</a></li>\
+                    <li><a href=\"https://example.org/2\">C</a></li></ul>"
+            })))
+            .with_body(r#"{"event_id": "$event"}"#)
+            .create_async()
+            .await;
+
+        let digest = ConfigFeedReceiverDigest {
+            schedule: None,
+            interval: Some(60),
+            title: None,
+            line: None,
+        };
+        let mut sent = 0;
+        receiver(&server.url(), "!room:example.org")
+            .send_digest(
+                &digest,
+                &[test_item("1", "A & B"), test_item("2", "C")],
+                &mut sent,
+            )
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,386 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{
+    config::{ConfigFeedReceiverDigest, ConfigFeedSlackReceiver, ConfigFeedSlackReceiverBlock},
+    database::DatabaseFeedItem,
+};
+
+use super::{
+    chunk_lines, digest_title, retry_rate_limited, trunc, Attempt, PermanentError, Receivable,
+};
+
+const DEFAULT_TEXT: &str = "$title";
+const DEFAULT_DIGEST_LINE: &str = "• <$link|$title>";
+
+const MAX_BLOCKS: usize = 50;
+const MAX_HEADER_LENGTH: usize = 150;
+const MAX_TEXT_LENGTH: usize = 3000;
+const MAX_CONTEXT_ELEMENTS: usize = 10;
+const MAX_ACTIONS: usize = 25;
+
+#[derive(Clone)]
+pub struct SlackReceiver {
+    pub config: ConfigFeedSlackReceiver,
+}
+
+impl Receivable for SlackReceiver {
+    fn supports_digests(&self) -> bool {
+        true
+    }
+
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        self.post(&self.build_message(item)).await?;
+
+        // incoming webhooks can't edit the messages they posted
+        Ok(None)
+    }
+
+    async fn send_digest(
+        &self,
+        digest: &ConfigFeedReceiverDigest,
+        items: &[DatabaseFeedItem],
+        sent: &mut usize,
+    ) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let title = digest_title(digest, items, str::to_owned);
+        let line = digest.line.as_deref().unwrap_or(DEFAULT_DIGEST_LINE);
+
+        // the lines are split across sections, and the sections across messages
+        let sections = chunk_lines(
+            String::new(),
+            items
+                .iter()
+                .map(|item| trunc(&item.sub_escaped(line, escape_mrkdwn), MAX_TEXT_LENGTH)),
+            MAX_TEXT_LENGTH,
+        );
+
+        // the header takes one of the blocks of the first message
+        for (i, chunk) in sections.chunks(MAX_BLOCKS - 1).enumerate() {
+            let mut blocks = Vec::new();
+
+            if i == 0 {
+                blocks.push(json!({
+                    "type": "header",
+                    "text": plain_text(&trunc(&title, MAX_HEADER_LENGTH))
+                }));
+            }
+
+            blocks.extend(
+                chunk
+                    .iter()
+                    .map(|(text, _)| json!({"type": "section", "text": mrkdwn(text)})),
+            );
+
+            self.post(&json!({"text": escape_mrkdwn(&title), "blocks": blocks}))
+                .await?;
+            *sent = chunk.last().map_or(*sent, |(_, count)| *count);
+        }
+
+        Ok(())
+    }
+}
+
+impl SlackReceiver {
+    pub fn new(config: &ConfigFeedSlackReceiver) -> Self {
+        SlackReceiver {
+            config: config.clone(),
+        }
+    }
+
+    /// Renders the blocks for the item, blocks whose text turns out empty are left out since
+    /// Slack rejects them, as are images and buttons of items without the url variables
+    fn build_message(&self, item: &DatabaseFeedItem) -> Value {
+        let text = item.sub_escaped(
+            self.config.text.as_deref().unwrap_or(DEFAULT_TEXT),
+            escape_mrkdwn,
+        );
+
+        let mut blocks: Vec<Value> = Vec::new();
+
+        for block in &self.config.blocks {
+            match block {
+                ConfigFeedSlackReceiverBlock::Header { text } => {
+                    let text = trunc(&item.sub(text), MAX_HEADER_LENGTH);
+                    if !text.trim().is_empty() {
+                        blocks.push(json!({"type": "header", "text": plain_text(&text)}));
+                    }
+                }
+                ConfigFeedSlackReceiverBlock::Section { text, image_url } => {
+                    let text = trunc(&item.sub_escaped(text, escape_mrkdwn), MAX_TEXT_LENGTH);
+                    if text.trim().is_empty() {
+                        continue;
+                    }
+
+                    let mut section = json!({"type": "section", "text": mrkdwn(&text)});
+
+                    if let Some(image_url) = image_url.as_deref().and_then(|u| url(item, u)) {
+                        section["accessory"] = json!({
+                            "type": "image",
+                            "image_url": image_url,
+                            "alt_text": alt_text(item)
+                        });
+                    }
+
+                    blocks.push(section);
+                }
+                ConfigFeedSlackReceiverBlock::Image {
+                    image_url,
+                    alt_text: alt,
+                    title,
+                } => {
+                    let Some(image_url) = url(item, image_url) else {
+                        continue;
+                    };
+
+                    let mut image = json!({
+                        "type": "image",
+                        "image_url": image_url,
+                        "alt_text": alt.as_ref().map(|a| trunc(&item.sub(a), 2000)).unwrap_or(alt_text(item))
+                    });
+
+                    if let Some(title) = title {
+                        image["title"] = plain_text(&trunc(&item.sub(title), 2000));
+                    }
+
+                    blocks.push(image);
+                }
+                ConfigFeedSlackReceiverBlock::Context { elements } => {
+                    let elements: Vec<Value> = elements
+                        .iter()
+                        .map(|e| trunc(&item.sub_escaped(e, escape_mrkdwn), MAX_TEXT_LENGTH))
+                        .filter(|e| !e.trim().is_empty())
+                        .take(MAX_CONTEXT_ELEMENTS)
+                        .map(|e| mrkdwn(&e))
+                        .collect();
+
+                    if !elements.is_empty() {
+                        blocks.push(json!({"type": "context", "elements": elements}));
+                    }
+                }
+                ConfigFeedSlackReceiverBlock::Button {
+                    text,
+                    url: button_url,
+                    style,
+                } => {
+                    let Some(button_url) = url(item, button_url) else {
+                        continue;
+                    };
+
+                    let mut button = json!({
+                        "type": "button",
+                        "text": plain_text(&trunc(&item.sub(text), 75)),
+                        "url": button_url
+                    });
+
+                    if let Some(style) = style {
+                        button["style"] = Value::String(style.clone());
+                    }
+
+                    // consecutive buttons share a single actions block
+                    match blocks.last_mut() {
+                        Some(last)
+                            if last["type"] == "actions"
+                                && last["elements"]
+                                    .as_array()
+                                    .is_some_and(|e| e.len() < MAX_ACTIONS) =>
+                        {
+                            last["elements"].as_array_mut().unwrap().push(button);
+                        }
+                        _ => blocks.push(json!({"type": "actions", "elements": [button]})),
+                    }
+                }
+                ConfigFeedSlackReceiverBlock::Divider => blocks.push(json!({"type": "divider"})),
+            }
+        }
+
+        blocks.truncate(MAX_BLOCKS);
+
+        let mut message = json!({"text": text});
+
+        if !blocks.is_empty() {
+            message["blocks"] = blocks.into();
+        }
+
+        message
+    }
+
+    /// Posts the message to the webhook, waiting for `Retry-After` when rate limited
+    async fn post(&self, message: &Value) -> Result<()> {
+        let client = reqwest::Client::new();
+
+        retry_rate_limited("Slack", || self.post_once(&client, message)).await
+    }
+
+    async fn post_once(&self, client: &reqwest::Client, message: &Value) -> Result<Attempt<()>> {
+        let resp = client
+            .post(&self.config.webhook_url)
+            .json(message)
+            .send()
+            .await?;
+
+        let status = resp.status();
+
+        if status.is_success() {
+            return Ok(Attempt::Done(()));
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(1);
+            return Ok(Attempt::RateLimited(Duration::from_secs(retry_after)));
+        }
+
+        // Slack answers with a plain text error code like invalid_blocks or no_service
+        let error = format!(
+            "Slack responded with {}: {}",
+            status,
+            resp.text().await.unwrap_or_default()
+        );
+
+        if status.is_client_error() {
+            return Err(PermanentError(error).into());
+        }
+
+        Err(anyhow!(error))
+    }
+}
+
+/// Escapes the characters Slack uses for links and mentions, see
+/// https://api.slack.com/reference/surfaces/formatting#escaping
+pub fn escape_mrkdwn(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn plain_text(text: &str) -> Value {
+    json!({"type": "plain_text", "text": text, "emoji": true})
+}
+
+fn mrkdwn(text: &str) -> Value {
+    json!({"type": "mrkdwn", "text": text})
+}
+
+/// Url rendered for the item, `None` when a variable is missing or it turns out empty since
+/// Slack rejects the blocks with an invalid url
+fn url(item: &DatabaseFeedItem, template: &str) -> Option<String> {
+    item.try_sub(template).filter(|u| !u.trim().is_empty())
+}
+
+/// Images require an alternative text, the title of the item describes them best
+fn alt_text(item: &DatabaseFeedItem) -> String {
+    item.variable("title")
+        .filter(|t| !t.trim().is_empty())
+        .map(|t| trunc(&t, 2000))
+        .unwrap_or(String::from("image"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_item;
+
+    fn receiver(blocks: Vec<ConfigFeedSlackReceiverBlock>) -> SlackReceiver {
+        SlackReceiver::new(&ConfigFeedSlackReceiver {
+            webhook_url: String::from("https://hooks.slack.com/services/T/B/x"),
+            text: None,
+            blocks,
+        })
+    }
+
+    fn media_blocks() -> Vec<ConfigFeedSlackReceiverBlock> {
+        vec![
+            ConfigFeedSlackReceiverBlock::Section {
+                text: String::from("*$title*"),
+                image_url: Some(String::from("$enclosure_url")),
+            },
+            ConfigFeedSlackReceiverBlock::Image {
+                image_url: String::from("$enclosure_url"),
+                alt_text: None,
+                title: None,
+            },
+            ConfigFeedSlackReceiverBlock::Button {
+                text: String::from("Read"),
+                url: String::from("$link"),
+                style: None,
+            },
+            ConfigFeedSlackReceiverBlock::Button {
+                text: String::from("Comments"),
+                url: String::from("$comments"),
+                style: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn renders_blocks_escaped() {
+        let mut item = test_item("1", "Fish & <chips>");
+        item.variables.insert(
+            String::from("enclosure_url"),
+            String::from("https://example.org/1.png"),
+        );
+
+        let message = receiver(media_blocks()).build_message(&item);
+
+        assert_eq!(message["text"], "Fish &amp; &lt;chips&gt;");
+        assert_eq!(
+            message["blocks"][0]["text"]["text"],
+            "*Fish &amp; &lt;chips&gt;*"
+        );
+        assert_eq!(
+            message["blocks"][0]["accessory"]["image_url"],
+            "https://example.org/1.png"
+        );
+        assert_eq!(message["blocks"][1]["alt_text"], "Fish & <chips>");
+        assert_eq!(
+            message["blocks"][2]["elements"][0]["url"],
+            "https://example.org/1"
+        );
+        assert_eq!(message["blocks"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn leaves_out_images_and_buttons_without_urls() {
+        let mut item = test_item("1", "Title");
+        item.variables
+            .insert(String::from("enclosure_url"), String::from(" "));
+        item.variables.remove("link");
+
+        let message = receiver(media_blocks()).build_message(&item);
+
+        assert_eq!(
+            message["blocks"],
+            json!([{"type": "section", "text": mrkdwn("*Title*")}])
+        );
+    }
+
+    #[test]
+    fn groups_consecutive_buttons() {
+        let buttons = (0..MAX_ACTIONS + 1)
+            .map(|i| ConfigFeedSlackReceiverBlock::Button {
+                text: i.to_string(),
+                url: String::from("$link"),
+                style: None,
+            })
+            .collect();
+
+        let message = receiver(buttons).build_message(&test_item("1", "Title"));
+
+        let blocks = message["blocks"].as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0]["elements"].as_array().unwrap().len(), MAX_ACTIONS);
+        assert_eq!(blocks[1]["elements"][0]["text"]["text"], "25");
+    }
+}
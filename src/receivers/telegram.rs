@@ -0,0 +1,573 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{
+        ConfigFeedReceiverDigest, ConfigFeedReceiverRemoval, ConfigFeedTelegramParseMode,
+        ConfigFeedTelegramReceiver,
+    },
+    database::DatabaseFeedItem,
+    markdown::{escape_html, html_to_text},
+};
+
+use super::{
+    chunk_lines, digest_title, retry_rate_limited, trunc, Attempt, PermanentError, Receivable,
+};
+
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+const MAX_TEXT_LENGTH: usize = 4096;
+const MAX_CAPTION_LENGTH: usize = 1024;
+
+/// Characters with a meaning in MarkdownV2, see https://core.telegram.org/bots/api#markdownv2-style
+const MARKDOWN_V2_CHARS: [char; 19] = [
+    '\\', '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!',
+];
+
+#[derive(Deserialize, Default)]
+struct TelegramResponse {
+    result: Option<Value>,
+    description: Option<String>,
+    parameters: Option<TelegramResponseParameters>,
+}
+
+#[derive(Deserialize)]
+struct TelegramResponseParameters {
+    retry_after: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct TelegramReceiver {
+    pub config: ConfigFeedTelegramReceiver,
+}
+
+impl Receivable for TelegramReceiver {
+    fn supports_digests(&self) -> bool {
+        true
+    }
+
+    fn supports_removal(&self, removal: ConfigFeedReceiverRemoval) -> bool {
+        removal != ConfigFeedReceiverRemoval::Retract
+    }
+
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        let text = self.render(item);
+        let photo = self
+            .config
+            .photo
+            .as_ref()
+            .and_then(|p| item.try_sub(p))
+            .filter(|p| !p.trim().is_empty());
+
+        // captions are a lot shorter than messages, long texts are sent without the photo
+        let (result, kind) = match photo {
+            Some(photo) if text.chars().count() <= MAX_CAPTION_LENGTH => {
+                let mut params = self.chat_params();
+                params["photo"] = Value::String(photo);
+                self.set_text(&mut params, "caption", text, MAX_CAPTION_LENGTH);
+                (self.call("sendPhoto", &params).await?, Some("photo"))
+            }
+            _ => {
+                let mut params = self.chat_params();
+                self.set_text(&mut params, "text", text, MAX_TEXT_LENGTH);
+                self.set_link_preview(&mut params);
+                (self.call("sendMessage", &params).await?, None)
+            }
+        };
+
+        // the message can be edited and deleted through the chat and its id
+        Ok(result["message_id"].as_i64().map(|id| {
+            let mut message_ref = format!("{}/{}", self.config.chat_id, id);
+            if let Some(kind) = kind {
+                message_ref.push('/');
+                message_ref.push_str(kind);
+            }
+            message_ref
+        }))
+    }
+
+    async fn send_digest(
+        &self,
+        digest: &ConfigFeedReceiverDigest,
+        items: &[DatabaseFeedItem],
+        sent: &mut usize,
+    ) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let title = digest_title(digest, items, self.escape());
+        let title = match self.config.parse_mode {
+            ConfigFeedTelegramParseMode::Html => format!("<b>{}</b>", title),
+            ConfigFeedTelegramParseMode::MarkdownV2 => format!("*{}*", title),
+        };
+
+        let line = digest
+            .line
+            .as_deref()
+            .unwrap_or(match self.config.parse_mode {
+                ConfigFeedTelegramParseMode::Html => "• <a href=\"$link\">$title</a>",
+                ConfigFeedTelegramParseMode::MarkdownV2 => "• [$title]($link)",
+            });
+
+        // lines are never cut, as that could break the markup
+        let messages = chunk_lines(
+            title,
+            items
+                .iter()
+                .map(|item| item.sub_escaped(line, self.escape())),
+            MAX_TEXT_LENGTH,
+        );
+
+        for (text, count) in messages {
+            let mut params = self.chat_params();
+            self.set_text(&mut params, "text", text, MAX_TEXT_LENGTH);
+            self.set_link_preview(&mut params);
+            self.call("sendMessage", &params).await?;
+            *sent = count;
+        }
+
+        Ok(())
+    }
+
+    async fn update_item(&self, item: &DatabaseFeedItem, message_ref: &str) -> Result<()> {
+        let (chat_id, message_id, photo) = split_message_ref(message_ref)?;
+
+        let mut params = json!({"chat_id": chat_id, "message_id": message_id});
+        let text = self.render(item);
+
+        if photo {
+            self.set_text(&mut params, "caption", text, MAX_CAPTION_LENGTH);
+            self.call("editMessageCaption", &params).await?;
+        } else {
+            self.set_text(&mut params, "text", text, MAX_TEXT_LENGTH);
+            self.set_link_preview(&mut params);
+            self.call("editMessageText", &params).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_item(&self, message_ref: &str) -> Result<()> {
+        let (chat_id, message_id, _) = split_message_ref(message_ref)?;
+
+        self.call(
+            "deleteMessage",
+            &json!({"chat_id": chat_id, "message_id": message_id}),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl TelegramReceiver {
+    pub fn new(config: &ConfigFeedTelegramReceiver) -> Self {
+        TelegramReceiver {
+            config: config.clone(),
+        }
+    }
+
+    fn escape(&self) -> fn(&str) -> String {
+        match self.config.parse_mode {
+            ConfigFeedTelegramParseMode::Html => escape_html,
+            ConfigFeedTelegramParseMode::MarkdownV2 => escape_markdown_v2,
+        }
+    }
+
+    fn render(&self, item: &DatabaseFeedItem) -> String {
+        let text = self
+            .config
+            .text
+            .as_deref()
+            .unwrap_or(match self.config.parse_mode {
+                ConfigFeedTelegramParseMode::Html => "<b>$title</b>\n$link",
+                ConfigFeedTelegramParseMode::MarkdownV2 => "*$title*\n$link",
+            });
+
+        item.sub_escaped(text, self.escape())
+    }
+
+    fn chat_params(&self) -> Value {
+        let mut params = json!({"chat_id": self.config.chat_id});
+
+        if let Some(thread_id) = self.config.message_thread_id {
+            params["message_thread_id"] = thread_id.into();
+        }
+
+        params
+    }
+
+    /// Sets the formatted text, texts over the limit are sent as truncated plain text since
+    /// cutting the markup would make Telegram reject it
+    fn set_text(&self, params: &mut Value, key: &str, text: String, limit: usize) {
+        if text.chars().count() <= limit {
+            params[key] = Value::String(text);
+            params["parse_mode"] = Value::String(String::from(match self.config.parse_mode {
+                ConfigFeedTelegramParseMode::Html => "HTML",
+                ConfigFeedTelegramParseMode::MarkdownV2 => "MarkdownV2",
+            }));
+            return;
+        }
+
+        let plain = match self.config.parse_mode {
+            ConfigFeedTelegramParseMode::Html => html_to_text(&text),
+            ConfigFeedTelegramParseMode::MarkdownV2 => unescape_markdown_v2(&text),
+        };
+
+        params[key] = Value::String(trunc(&plain, limit));
+    }
+
+    fn set_link_preview(&self, params: &mut Value) {
+        if self.config.disable_web_page_preview {
+            params["link_preview_options"] = json!({"is_disabled": true});
+        }
+    }
+
+    /// Calls a Bot API method, waiting as long as Telegram asks when rate limited
+    async fn call(&self, method: &str, params: &Value) -> Result<Value> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config
+                .api_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/'),
+            self.config.bot_token,
+            method
+        );
+
+        let client = reqwest::Client::new();
+
+        retry_rate_limited("Telegram", || call_once(&client, &url, params)).await
+    }
+}
+
+async fn call_once(client: &reqwest::Client, url: &str, params: &Value) -> Result<Attempt<Value>> {
+    // the url contains the bot token, which should not end up in the logs
+    let resp = client
+        .post(url)
+        .json(params)
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+
+    let status = resp.status();
+    let body: TelegramResponse = resp.json().await.unwrap_or_default();
+
+    if status.is_success() {
+        return Ok(Attempt::Done(body.result.unwrap_or_default()));
+    }
+
+    let description = body.description.unwrap_or_default();
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = body.parameters.and_then(|p| p.retry_after).unwrap_or(1);
+        return Ok(Attempt::RateLimited(Duration::from_secs(retry_after)));
+    }
+
+    // editing a message without changing it is an error for Telegram
+    if description.contains("message is not modified") {
+        return Ok(Attempt::Done(Value::Null));
+    }
+
+    let error = format!("Telegram responded with {}: {}", status, description);
+
+    if status.is_client_error() {
+        return Err(PermanentError(error).into());
+    }
+
+    Err(anyhow!(error))
+}
+
+fn escape_markdown_v2(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+
+    for c in input.chars() {
+        if MARKDOWN_V2_CHARS.contains(&c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+
+    output
+}
+
+fn unescape_markdown_v2(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next)) if MARKDOWN_V2_CHARS.contains(&next) => {
+                output.push(next);
+                chars.next();
+            }
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
+
+/// Splits the reference into the chat, the message id and whether the message is a photo
+fn split_message_ref(message_ref: &str) -> Result<(&str, i64, bool)> {
+    let invalid = || PermanentError(format!("invalid message reference {}", message_ref));
+
+    let mut parts = message_ref.split('/');
+    let chat_id = parts.next().ok_or_else(invalid)?;
+    let message_id = parts
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(invalid)?;
+
+    Ok((chat_id, message_id, parts.next() == Some("photo")))
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server};
+
+    use super::*;
+    use crate::database::test_item;
+
+    fn receiver(api_url: &str, parse_mode: ConfigFeedTelegramParseMode) -> TelegramReceiver {
+        TelegramReceiver::new(&ConfigFeedTelegramReceiver {
+            api_url: Some(format!("{}/", api_url)),
+            bot_token: String::from("123:token"),
+            chat_id: String::from("-100123"),
+            message_thread_id: None,
+            parse_mode,
+            text: None,
+            photo: None,
+            disable_web_page_preview: false,
+        })
+    }
+
+    #[test]
+    fn escapes_markdown_v2() {
+        let escaped = escape_markdown_v2("a_b *c* [d](e) 1.5! \\");
+        assert_eq!(escaped, "a\\_b \\*c\\* \\[d\\]\\(e\\) 1\\.5\\! \\\\");
+        assert_eq!(unescape_markdown_v2(&escaped), "a_b *c* [d](e) 1.5! \\");
+        assert_eq!(unescape_markdown_v2("a\\b\\"), "a\\b\\");
+    }
+
+    #[tokio::test]
+    async fn sends_messages_escaped() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/bot123:token/sendMessage")
+            .match_body(Matcher::Json(json!({
+                "chat_id": "-100123",
+                "text": "<b>Fish &amp; &lt;chips&gt;</b>\nhttps://example.org/1",
+                "parse_mode": "HTML"
+            })))
+            .with_body(r#"{"ok": true, "result": {"message_id": 42}}"#)
+            .create_async()
+            .await;
+
+        let message_ref = receiver(&server.url(), ConfigFeedTelegramParseMode::Html)
+            .send_item(&test_item("1", "Fish & <chips>"))
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+        assert_eq!(message_ref.as_deref(), Some("-100123/42"));
+    }
+
+    #[tokio::test]
+    async fn sends_long_texts_as_plain_text() {
+        let mut server = Server::new_async().await;
+        let title = "a.".repeat(MAX_TEXT_LENGTH);
+        let mock = server
+            .mock("POST", "/bot123:token/sendMessage")
+            .match_body(Matcher::Json(json!({
+                "chat_id": "-100123",
+                "text": trunc(&format!("{}\nhttps://example.org/1", title), MAX_TEXT_LENGTH)
+            })))
+            .with_body(r#"{"ok": true, "result": {"message_id": 42}}"#)
+            .create_async()
+            .await;
+
+        let mut receiver = receiver(&server.url(), ConfigFeedTelegramParseMode::MarkdownV2);
+        receiver.config.text = Some(String::from("$title\n$link"));
+        receiver.send_item(&test_item("1", &title)).await.unwrap();
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn edits_photo_captions() {
+        let mut server = Server::new_async().await;
+        let sent = server
+            .mock("POST", "/bot123:token/sendPhoto")
+            .match_body(Matcher::PartialJson(json!({
+                "photo": "https://example.org/1.png",
+                "caption": "<b>Title</b>\nhttps://example.org/1"
+            })))
+            .with_body(r#"{"ok": true, "result": {"message_id": 42}}"#)
+            .create_async()
+            .await;
+        let edited = server
+            .mock("POST", "/bot123:token/editMessageCaption")
+            .match_body(Matcher::Json(json!({
+                "chat_id": "-100123",
+                "message_id": 42,
+                "caption": "<b>New</b>\nhttps://example.org/1",
+                "parse_mode": "HTML"
+            })))
+            .with_body(r#"{"ok": true, "result": {"message_id": 42}}"#)
+            .create_async()
+            .await;
+
+        let mut receiver = receiver(&server.url(), ConfigFeedTelegramParseMode::Html);
+        receiver.config.photo = Some(String::from("$link.png"));
+
+        let message_ref = receiver
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message_ref, "-100123/42/photo");
+        receiver
+            .update_item(&test_item("1", "New"), &message_ref)
+            .await
+            .unwrap();
+
+        sent.assert_async().await;
+        edited.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn ignores_edits_without_changes() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/bot123:token/editMessageText")
+            .match_body(Matcher::PartialJson(json!({"chat_id": "-100123", "message_id": 42})))
+            .with_status(400)
+            .with_body(
+                r#"{"ok": false, "description": "Bad Request: message is not modified: specified new message content and reply markup are exactly the same"}"#,
+            )
+            .create_async()
+            .await;
+
+        receiver(&server.url(), ConfigFeedTelegramParseMode::Html)
+            .update_item(&test_item("1", "Title"), "-100123/42")
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn deletes_messages() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/bot123:token/deleteMessage")
+            .match_body(Matcher::Json(
+                json!({"chat_id": "-100123", "message_id": 42}),
+            ))
+            .with_body(r#"{"ok": true, "result": true}"#)
+            .create_async()
+            .await;
+
+        receiver(&server.url(), ConfigFeedTelegramParseMode::Html)
+            .delete_item("-100123/42/photo")
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn retries_when_rate_limited() {
+        let mut server = Server::new_async().await;
+        let limited = server
+            .mock("POST", "/bot123:token/sendMessage")
+            .with_status(429)
+            .with_body(r#"{"ok": false, "description": "Too Many Requests", "parameters": {"retry_after": 0}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let sent = server
+            .mock("POST", "/bot123:token/sendMessage")
+            .with_body(r#"{"ok": true, "result": {"message_id": 42}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let message_ref = receiver(&server.url(), ConfigFeedTelegramParseMode::Html)
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap();
+
+        limited.assert_async().await;
+        sent.assert_async().await;
+        assert_eq!(message_ref.as_deref(), Some("-100123/42"));
+    }
+
+    #[tokio::test]
+    async fn fails_permanently_on_client_errors() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/bot123:token/sendMessage")
+            .with_status(403)
+            .with_body(r#"{"ok": false, "description": "Forbidden: bot was kicked"}"#)
+            .create_async()
+            .await;
+
+        let err = receiver(&server.url(), ConfigFeedTelegramParseMode::Html)
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap_err();
+
+        assert!(err.is::<PermanentError>());
+        assert!(err.to_string().contains("bot was kicked"));
+        assert!(!err.to_string().contains("123:token"));
+    }
+
+    #[tokio::test]
+    async fn counts_the_items_of_the_digest_parts_sent() {
+        let mut server = Server::new_async().await;
+        let first = server
+            .mock("POST", "/bot123:token/sendMessage")
+            .match_body(Matcher::Regex(String::from(
+                r#""text":"<b>5 new items</b>\\n"#,
+            )))
+            .with_body(r#"{"ok": true, "result": {"message_id": 42}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let second = server
+            .mock("POST", "/bot123:token/sendMessage")
+            .with_status(502)
+            .expect(1)
+            .create_async()
+            .await;
+
+        // four lines fit in the first message, the fifth goes in a second one
+        let items: Vec<_> = (0..5)
+            .map(|i| test_item(&i.to_string(), &"a".repeat(1000)))
+            .collect();
+        let digest = ConfigFeedReceiverDigest {
+            schedule: None,
+            interval: Some(60),
+            title: None,
+            line: Some(String::from("$title")),
+        };
+        let mut sent = 0;
+        let err = receiver(&server.url(), ConfigFeedTelegramParseMode::Html)
+            .send_digest(&digest, &items, &mut sent)
+            .await
+            .unwrap_err();
+
+        first.assert_async().await;
+        second.assert_async().await;
+        assert!(!err.is::<PermanentError>());
+        assert_eq!(sent, 4);
+    }
+}
@@ -0,0 +1,330 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Method, StatusCode,
+};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{config::ConfigFeedWebhookReceiver, database::DatabaseFeedItem};
+
+use super::{PermanentError, Receivable};
+
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature-256";
+
+/// Methods that can be configured, an unknown method is more likely a typo than an extension
+const METHODS: [Method; 7] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::OPTIONS,
+    Method::PATCH,
+];
+
+#[derive(Clone)]
+pub struct WebhookReceiver {
+    pub config: ConfigFeedWebhookReceiver,
+    method: Method,
+    /// Header names with the templates of their values
+    headers: Vec<(HeaderName, String)>,
+    signature_header: HeaderName,
+}
+
+impl Receivable for WebhookReceiver {
+    async fn send_item(&self, item: &DatabaseFeedItem) -> Result<Option<String>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let value = HeaderValue::from_str(&item.sub(value))
+                .map_err(|_| PermanentError(format!("invalid value for header {}", name)))?;
+            headers.insert(name.clone(), value);
+        }
+
+        let (body, content_type) = match (&self.config.json, &self.config.body) {
+            (Some(json), _) => (
+                serde_json::to_string(&expand(json, item))?,
+                "application/json",
+            ),
+            (None, Some(body)) => (item.sub(body), "text/plain; charset=utf-8"),
+            (None, None) => (String::new(), "text/plain; charset=utf-8"),
+        };
+
+        if !headers.contains_key(CONTENT_TYPE) {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+
+        if let Some(secret) = &self.config.secret {
+            headers.insert(
+                self.signature_header.clone(),
+                HeaderValue::from_str(&sign(secret, &body)?)?,
+            );
+        }
+
+        let resp = reqwest::Client::new()
+            .request(self.method.clone(), item.sub(&self.config.url))
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+
+        let success = if self.config.success_codes.is_empty() {
+            status.is_success()
+        } else {
+            self.config.success_codes.contains(&status.as_u16())
+        };
+
+        if success {
+            return Ok(None);
+        }
+
+        let error = format!(
+            "Webhook responded with {}: {}",
+            status,
+            resp.text().await.unwrap_or_default()
+        );
+
+        // the endpoint may recover from server errors, timeouts and rate limits
+        if status.is_client_error()
+            && status != StatusCode::REQUEST_TIMEOUT
+            && status != StatusCode::TOO_MANY_REQUESTS
+        {
+            return Err(PermanentError(error).into());
+        }
+
+        Err(anyhow!(error))
+    }
+}
+
+impl WebhookReceiver {
+    /// Parses the method and header names, so a typo fails when the config is loaded instead
+    /// of dead-lettering every item
+    pub fn new(config: &ConfigFeedWebhookReceiver) -> Result<Self> {
+        let method = match &config.method {
+            Some(method) => Method::from_str(&method.to_uppercase())
+                .ok()
+                .filter(|m| METHODS.contains(m))
+                .ok_or_else(|| anyhow!("invalid method {}", method))?,
+            None => Method::POST,
+        };
+
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| Ok((parse_header_name(name)?, value.clone())))
+            .collect::<Result<_>>()?;
+
+        let signature_header = parse_header_name(
+            config
+                .signature_header
+                .as_deref()
+                .unwrap_or(DEFAULT_SIGNATURE_HEADER),
+        )?;
+
+        Ok(WebhookReceiver {
+            config: config.clone(),
+            method,
+            headers,
+            signature_header,
+        })
+    }
+}
+
+fn parse_header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::from_str(name).map_err(|_| anyhow!("invalid header name {}", name))
+}
+
+/// Expands every string in the template, including the keys of objects
+fn expand(template: &Value, item: &DatabaseFeedItem) -> Value {
+    match template {
+        Value::String(s) => Value::String(item.sub(s)),
+        Value::Array(values) => Value::Array(values.iter().map(|v| expand(v, item)).collect()),
+        Value::Object(values) => Value::Object(
+            values
+                .iter()
+                .map(|(k, v)| (item.sub(k), expand(v, item)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// HMAC-SHA256 of the body with the shared secret, formatted as `sha256=<hex>`
+fn sign(secret: &str, body: &str) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(body.as_bytes());
+
+    Ok(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server};
+    use serde_json::json;
+
+    use super::*;
+    use crate::database::test_item;
+
+    fn config(url: &str) -> ConfigFeedWebhookReceiver {
+        ConfigFeedWebhookReceiver {
+            url: url.to_owned(),
+            method: None,
+            headers: Default::default(),
+            json: None,
+            body: None,
+            secret: None,
+            signature_header: None,
+            success_codes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?").unwrap(),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn expands_nested_templates_and_keys() {
+        let template = json!({
+            "text": "$title",
+            "$title": [1, "$link", {"nested": "$missing"}],
+            "keep": [true, null, 2.5]
+        });
+
+        assert_eq!(
+            expand(&template, &test_item("1", "Title")),
+            json!({
+                "text": "Title",
+                "Title": [1, "https://example.org/1", {"nested": "$missing"}],
+                "keep": [true, null, 2.5]
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let mut invalid = config("https://example.org/hook");
+        invalid.method = Some(String::from("PSOT"));
+        assert!(WebhookReceiver::new(&invalid).is_err());
+
+        let mut invalid = config("https://example.org/hook");
+        invalid.headers = [(String::from("X Token"), String::from("x"))].into();
+        assert!(WebhookReceiver::new(&invalid).is_err());
+
+        let mut invalid = config("https://example.org/hook");
+        invalid.signature_header = Some(String::from("X-Signature:"));
+        assert!(WebhookReceiver::new(&invalid).is_err());
+
+        let mut valid = config("https://example.org/hook");
+        valid.method = Some(String::from("put"));
+        assert_eq!(WebhookReceiver::new(&valid).unwrap().method, Method::PUT);
+    }
+
+    #[tokio::test]
+    async fn sends_signed_requests() {
+        let mut server = Server::new_async().await;
+        let body = r#"{"title":"Title"}"#;
+        let mock = server
+            .mock("PUT", "/hook/1")
+            .match_header("content-type", "application/json")
+            .match_header("x-token", "Title")
+            .match_header(
+                "x-hub-signature-256",
+                sign("secret", body).unwrap().as_str(),
+            )
+            .match_body(Matcher::Exact(body.to_owned()))
+            .create_async()
+            .await;
+
+        let mut config = config(&format!("{}/hook/$external_id", server.url()));
+        config.method = Some(String::from("PUT"));
+        config.headers = [(String::from("X-Token"), String::from("$title"))].into();
+        config.json = Some(json!({"title": "$title"}));
+        config.secret = Some(String::from("secret"));
+        config.signature_header = Some(String::from("X-Hub-Signature-256"));
+
+        let mut item = test_item("1", "Title");
+        item.variables
+            .insert(String::from("external_id"), String::from("1"));
+
+        let message_ref = WebhookReceiver::new(&config)
+            .unwrap()
+            .send_item(&item)
+            .await
+            .unwrap();
+
+        mock.assert_async().await;
+        assert_eq!(message_ref, None);
+    }
+
+    #[tokio::test]
+    async fn counts_only_the_success_codes_as_delivered() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/accepted")
+            .with_status(202)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/created")
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let mut config = config(&format!("{}/accepted", server.url()));
+        config.success_codes = vec![202];
+        let receiver = WebhookReceiver::new(&config).unwrap();
+        receiver.send_item(&test_item("1", "Title")).await.unwrap();
+
+        config.url = format!("{}/created", server.url());
+        let receiver = WebhookReceiver::new(&config).unwrap();
+        let err = receiver
+            .send_item(&test_item("1", "Title"))
+            .await
+            .unwrap_err();
+        assert!(!err.is::<PermanentError>());
+    }
+
+    #[tokio::test]
+    async fn retries_only_recoverable_errors() {
+        let mut server = Server::new_async().await;
+
+        for (status, permanent) in [
+            (400, true),
+            (404, true),
+            (408, false),
+            (429, false),
+            (503, false),
+        ] {
+            let mock = server
+                .mock("POST", "/hook")
+                .with_status(status)
+                .with_body("nope")
+                .create_async()
+                .await;
+
+            let receiver =
+                WebhookReceiver::new(&config(&format!("{}/hook", server.url()))).unwrap();
+            let err = receiver
+                .send_item(&test_item("1", "Title"))
+                .await
+                .unwrap_err();
+
+            assert_eq!(err.is::<PermanentError>(), permanent, "{}", status);
+            assert!(err.to_string().ends_with(": nope"));
+            mock.remove_async().await;
+        }
+    }
+}
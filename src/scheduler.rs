@@ -6,6 +6,8 @@ use tokio_cron_scheduler::{Job, JobScheduler};
 
 use crate::{config::ConfigFeed, database::Database, feed::Feed};
 
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct Scheduler {
     scheduler: JobScheduler,
 }
@@ -14,8 +16,10 @@ impl Scheduler {
     pub async fn init(feeds: Vec<ConfigFeed>, database: Database) -> Result<Self> {
         let scheduler = JobScheduler::new().await.unwrap();
 
+        let delivery_database = database.clone();
+
         for feed_config in feeds.clone() {
-            let feed = Feed::from_config(feed_config.clone());
+            let feed = Feed::from_config(feed_config.clone())?;
             let database = database.clone();
 
             let job =
@@ -30,6 +34,22 @@ impl Scheduler {
                 })?;
 
             scheduler.add(job).await?;
+
+            // retries failed deliveries independently of the feed interval
+            let feed = Feed::from_config(feed_config.clone())?;
+            let database = delivery_database.clone();
+
+            let delivery_job = Job::new_repeated_async(DELIVERY_INTERVAL, move |_, _| {
+                let feed = feed.clone();
+                let database = database.clone();
+                Box::pin(async move {
+                    if let Err(e) = feed.deliver(&database).await {
+                        warn!("Error delivering items of feed {}: {}", feed.id, e)
+                    }
+                })
+            })?;
+
+            scheduler.add(delivery_job).await?;
         }
 
         Ok(Scheduler { scheduler })